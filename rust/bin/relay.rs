@@ -30,7 +30,9 @@ fn main() {
         init().unwrap();
     }
 
-    let addressbook = Arc::new(Mutex::new(AddressBook::read(&pmail::pmail::relay_dir().unwrap()).unwrap()));
+    let passphrase = pmail::keystore::passphrase().unwrap();
+    let addressbook = Arc::new(Mutex::new(AddressBook::read(&pmail::pmail::relay_dir().unwrap(),
+                                                            &passphrase).unwrap()));
 
     let response_keys = crypto::box_keypair();
     let secret_key_for_http = response_keys.public.0;
@@ -68,7 +70,9 @@ fn main() {
     loop {
         std::thread::sleep_ms(1000*30); // sleep a while before doing a pickup...
         let mut addressbook = addressbook.lock().unwrap();
-        addressbook.pickup();
+        if let Err(e) = addressbook.pickup() {
+            info!("Unable to pick up messages: {}", e);
+        }
         if let Some((p,msg_id,m)) = addressbook.listen() {
             info!("I got personal message {:?} with id {}!", m, msg_id);
             match m {
@@ -91,12 +95,11 @@ fn main() {
                     addressbook.assert_secret_id(&user, &key);
                 },
                 Message::Acknowledge { msg_id } => {
-                    info!("Acknowledgement of message {}", dht::codename(&msg_id.0));
+                    addressbook.acknowledge(&p, &msg_id);
                 },
                 Message::Comment { contents, message_length, message_start, .. } => {
                     info!("Got comment from {}", p);
                     let message_length = message_length as usize;
-                    info!("    {}", &std::str::from_utf8(&contents[0 .. message_length]).unwrap());
                     if message_start == 0 && message_length <= contents.len() {
                         let s = String::from_utf8_lossy(&contents[0..message_length]).to_string();
                         info!("Nice comment! {}", s);
@@ -127,14 +130,22 @@ fn main() {
                         addressbook.send(&p, &ack);
                     }
                 },
-                _ => {
-                    println!("\r\nI got message {:?}!\r\n", m);
-                    info!("I heard something fun from {}!",
-                          dht::codename(&p.0));
-                    println!("\r\nI heard something fun from {}! i.e. {}\r\n",
-                             dht::codename(&p.0), p);
-                    std::thread::sleep_ms(1000000);
-                    panic!("the end");
+                Message::ThreadSubject { .. } | Message::ThreadRecipients { .. } => {
+                    info!("Ignoring thread information from {}", p);
+                    let ack = Message::Acknowledge {
+                        msg_id: msg_id,
+                    };
+                    addressbook.send(&p, &ack);
+                },
+                Message::Rekey { .. } => {
+                    // `listen` handles these itself.
+                },
+                Message::Unknown { tag } => {
+                    info!("Ignoring message of unknown kind {} from {}", tag, p);
+                    let ack = Message::Acknowledge {
+                        msg_id: msg_id,
+                    };
+                    addressbook.send(&p, &ack);
                 },
             }
         }
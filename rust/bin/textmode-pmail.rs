@@ -22,7 +22,6 @@ use pmail::pmail::{AddressBook, Message, Thread};
 use pmail::str255::{Str255};
 use pmail::dht;
 use pmail::mailbox;
-use pmail::udp;
 use pmail::format;
 
 struct LogData {
@@ -166,7 +165,9 @@ fn main() {
         }
     };
 
-    let mut addressbook = AddressBook::read(&pmail::pmail::pmail_dir().unwrap()).unwrap();
+    // We need the passphrase before rustbox takes over the terminal.
+    let passphrase = pmail::keystore::passphrase().unwrap();
+    let mut addressbook = AddressBook::read(&pmail::pmail::pmail_dir().unwrap(), &passphrase).unwrap();
     let mut mailbox = mailbox::Mailbox::new().unwrap();
     let mut which_thread = 0;
     let mut selected_user = 0;
@@ -222,6 +223,27 @@ fn main() {
                         nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
                     }
                     Some(Key::Ctrl('u')) => { us = UserState::FindUser; }
+                    Some(Key::Ctrl('g')) => {
+                        // Start a group thread with the selected user and
+                        // everyone named (comma-separated) in the editor.
+                        if us == UserState::Messages {
+                            let mut recipients = vec![which_userkey_selected(&addressbook, selected_user)];
+                            for name in editing.split(',').map(|n| { n.trim() }).filter(|n| { n.len() > 0 }) {
+                                match addressbook.lookup(name) {
+                                    Some(k) => { recipients.push(k); }
+                                    None => { info!("Unknown user \"{}\"", name); }
+                                }
+                            }
+                            let thread = Thread::random();
+                            info!("Starting group thread {} with {} others", thread, recipients.len());
+                            let me = addressbook.my_key();
+                            for (msg_id, m) in addressbook.announce_thread(thread, &recipients, None) {
+                                mailbox.save(msg_id, &me, &me, &m).unwrap();
+                            }
+                            *editing = String::new();
+                            nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
+                        }
+                    }
                     Some(Key::Char(c)) => { editing.push(c); }
                     Some(Key::Enter) => {
                         match us {
@@ -240,24 +262,22 @@ fn main() {
                             }
                             UserState::Messages => {
                                 if editing.len() == 0 { continue; }
-                                let mess: Str255 = Str255::from(editing.as_ref());
                                 let name = which_user_selected(&addressbook, selected_user);
                                 info!("Message \"{}\" to \"{}\"", editing, name);
-                                let mut c = [0u8; 394];
-                                for i in 0 .. mess.length as usize {
-                                    c[i] = mess.content[i];
-                                }
-                                let m = Message::Comment {
-                                    thread: the_current_thread(&mailbox, selected_user, which_thread, &addressbook),
-                                    time: udp::epoch_time(),
-                                    message_length: editing.len() as u32,
-                                    message_start: 0,
-                                    contents: c,
-                                };
+                                let thread = the_current_thread(&mailbox, selected_user, which_thread, &addressbook);
                                 if let Some(k) = addressbook.lookup(&name) {
-                                    let msg_id = addressbook.send(&k, &m);
-                                    mailbox.save(msg_id, &addressbook.my_key(),
-                                                 &which_userkey_selected(&addressbook, selected_user), &m).unwrap();
+                                    let mut recipients = mailbox.thread_recipients(thread);
+                                    if recipients.len() == 0 {
+                                        recipients.push(k);
+                                    }
+                                    match addressbook.send_comment(&recipients, thread, &editing[..]) {
+                                        Ok(sent) => {
+                                            for (msg_id, m) in sent {
+                                                mailbox.save(msg_id, &addressbook.my_key(), &k, &m).unwrap();
+                                            }
+                                        },
+                                        Err(e) => info!("Unable to send message: {}", e),
+                                    }
                                 }
                                 nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
                             }
@@ -294,8 +314,17 @@ fn main() {
         }
         count_to_pickup += 1;
         if count_to_pickup == 120 { // this is very hokey...
-            addressbook.pickup();
+            if let Err(e) = addressbook.pickup() {
+                info!("Unable to pick up messages: {}", e);
+            }
             count_to_pickup = 0;
+            let failures = addressbook.take_failures();
+            for msg_id in failures.iter() {
+                info!("Gave up on delivering message {}", dht::codename(&msg_id.0));
+            }
+            if failures.len() > 0 {
+                nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
+            }
         }
         if let Some((p,msg_id,m)) = addressbook.listen() {
             info!("I got personal message {:?}!", m);
@@ -318,9 +347,8 @@ fn main() {
                     info!("A user response about {}", user);
                     addressbook.assert_secret_id(&user, &key);
                 },
-                Message::Comment { contents, message_length, .. } => {
-                    info!("Got comment from {}", p);
-                    info!("    {}", &std::str::from_utf8(&contents[0 .. message_length as usize]).unwrap());
+                Message::Comment { message_length, message_start, .. } => {
+                    info!("Got comment from {} ({} of {} bytes)", p, message_start, message_length);
                     mailbox.save(msg_id, &p, &addressbook.my_key(), &m).unwrap();
                     nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
                     let ack = Message::Acknowledge {
@@ -329,17 +357,30 @@ fn main() {
                     info!("Sending acknowledgement to {}!", dht::codename(&p.0));
                     addressbook.send(&p, &ack);
                 },
+                Message::ThreadSubject { .. } | Message::ThreadRecipients { .. } => {
+                    info!("Got thread information from {}", p);
+                    mailbox.save(msg_id, &p, &addressbook.my_key(), &m).unwrap();
+                    nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
+                    let ack = Message::Acknowledge {
+                        msg_id: msg_id,
+                    };
+                    addressbook.send(&p, &ack);
+                },
                 Message::Acknowledge { msg_id } => {
-                    info!("Acknowledgement of message {}", dht::codename(&msg_id.0));
+                    if let Err(e) = addressbook.acknowledge_delivery(&mut mailbox, &p, &msg_id) {
+                        info!("Unable to record delivery of {}: {}", dht::codename(&msg_id.0), e);
+                    }
+                    nice_comments = format_messages(&mailbox, selected_user, which_thread, &addressbook);
+                },
+                Message::Rekey { .. } => {
+                    // `listen` handles these itself.
                 },
-                _ => {
-                    println!("\r\nI got message {:?}!\r\n", m);
-                    info!("I heard something fun from {}!",
-                          dht::codename(&p.0));
-                    println!("\r\nI heard something fun from {}! i.e. {}\r\n",
-                             dht::codename(&p.0), p);
-                    std::thread::sleep_ms(1000000);
-                    panic!("the end");
+                Message::Unknown { tag } => {
+                    info!("Got a message of unknown kind {} from {}", tag, p);
+                    let ack = Message::Acknowledge {
+                        msg_id: msg_id,
+                    };
+                    addressbook.send(&p, &ack);
                 },
             }
         }
@@ -447,18 +488,42 @@ fn format_messages(mb: &mailbox::Mailbox, which_user: usize, which_thread: usize
     let thread = the_current_thread(mb, which_user, which_thread, ab);
 
     nice_comments.push(format!("thread: {}", thread));
+    if let Some(subject) = mb.thread_subject(thread) {
+        nice_comments.push(format!("subject: {}", subject));
+    }
+    let recipients = mb.thread_recipients(thread);
+    if recipients.len() > 0 {
+        let names: Vec<String> = recipients.iter().map(|k| {
+            ab.reverse_lookup(k).unwrap_or_else(|| { dht::codename(&k.0) })
+        }).collect();
+        nice_comments.push(format!("with: {}", names.join(", ")));
+    }
     for msg in mb.comments_in_thread(thread) {
+        let delivered = mb.deliveries(thread, msg.id).len();
+        let state = if delivered > 0 && recipients.len() > 2 {
+            format!(" (delivered to {})", delivered)
+        } else if delivered > 0 {
+            format!(" (delivered)")
+        } else {
+            match ab.delivery_state(&msg.id) {
+                Some(format::DeliveryState::Queued) => format!(" (queued)"),
+                Some(format::DeliveryState::Sent) => format!(" (sent)"),
+                Some(format::DeliveryState::Acknowledged) => format!(" (delivered)"),
+                Some(format::DeliveryState::GaveUp) => format!(" (failed)"),
+                None => String::new(),
+            }
+        };
         match ab.reverse_lookup(&msg.from) {
             Some(user) => {
-                nice_comments.push(format!("[{}] {}: {}",
+                nice_comments.push(format!("[{}] {}: {}{}",
                                            format_date_ago(msg.time),
-                                           user, &msg.contents));
+                                           user, &msg.contents, state));
             }
             None => {
-                nice_comments.push(format!("[{}] {}: {}",
+                nice_comments.push(format!("[{}] {}: {}{}",
                                            format_date_ago(msg.time),
                                            dht::codename(&msg.from.0),
-                                           &msg.contents));
+                                           &msg.contents, state));
             }
         }
     }
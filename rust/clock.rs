@@ -0,0 +1,113 @@
+//! Where the `dht` gets the time from.  Normally this is the system
+//! clock, but tests can use a `FakeClock` to run an hour of traffic
+//! in a few milliseconds.
+
+use std;
+use std::sync::{Arc, Mutex, Condvar};
+
+use udp;
+
+/// A source of time, measured in milliseconds since `udp::EPOCH`.
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u64;
+    /// Sleep until `ms_from_epoch`.  Returns false (without sleeping)
+    /// if that time has already passed.
+    fn sleep_until(&self, ms_from_epoch: u64) -> bool;
+    /// The time in seconds since `udp::EPOCH`.
+    fn epoch_time(&self) -> u32 {
+        (self.now_ms()/1000) as u32
+    }
+}
+
+/// The real time.
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        udp::now_ms()
+    }
+    fn sleep_until(&self, ms_from_epoch: u64) -> bool {
+        udp::sleep_until(ms_from_epoch)
+    }
+}
+
+/// A clock that only moves when told to.  Anyone sleeping on it wakes
+/// up once it is advanced far enough.  Clones share the same time.
+#[derive(Clone)]
+pub struct FakeClock {
+    now: Arc<(Mutex<FakeTime>, Condvar)>,
+}
+
+struct FakeTime {
+    ms: u64,
+    stopped: bool,
+}
+
+impl FakeClock {
+    pub fn new(start_ms: u64) -> FakeClock {
+        FakeClock {
+            now: Arc::new((Mutex::new(FakeTime { ms: start_ms, stopped: false }), Condvar::new())),
+        }
+    }
+    pub fn advance(&self, ms: u64) {
+        let &(ref now, ref cond) = &*self.now;
+        now.lock().unwrap().ms += ms;
+        cond.notify_all();
+    }
+    pub fn set(&self, ms_from_epoch: u64) {
+        let &(ref now, ref cond) = &*self.now;
+        now.lock().unwrap().ms = ms_from_epoch;
+        cond.notify_all();
+    }
+    /// Wake everyone sleeping on this clock, and never let anyone
+    /// sleep on it again, so that the threads of nodes using it can
+    /// notice that they are being shut down without anyone having to
+    /// keep the clock moving.
+    pub fn stop(&self) {
+        let &(ref now, ref cond) = &*self.now;
+        now.lock().unwrap().stopped = true;
+        cond.notify_all();
+    }
+}
+
+impl Clock for FakeClock {
+    fn now_ms(&self) -> u64 {
+        self.now.0.lock().unwrap().ms
+    }
+    /// Once the clock is stopped, this returns false straight away.
+    fn sleep_until(&self, ms_from_epoch: u64) -> bool {
+        let &(ref now, ref cond) = &*self.now;
+        let mut t = now.lock().unwrap();
+        if t.ms > ms_from_epoch {
+            return false;
+        }
+        while t.ms < ms_from_epoch {
+            if t.stopped {
+                return false;
+            }
+            t = cond.wait(t).unwrap();
+        }
+        true
+    }
+}
+
+#[test]
+fn test_fake_clock() {
+    let clock = FakeClock::new(1000);
+    assert_eq!(clock.now_ms(), 1000);
+    assert_eq!(clock.epoch_time(), 1);
+    assert!(!clock.sleep_until(999));
+    let sleeper = clock.clone();
+    let t = std::thread::spawn(move|| { sleeper.sleep_until(5000) });
+    clock.advance(3000);
+    clock.advance(1000);
+    assert!(t.join().unwrap());
+    assert_eq!(clock.now_ms(), 5000);
+    let sleeper = clock.clone();
+    let t = std::thread::spawn(move|| { sleeper.sleep_until(9000) });
+    clock.stop();
+    assert!(!t.join().unwrap());
+    assert!(!clock.sleep_until(9000));
+    assert_eq!(clock.now_ms(), 5000);
+}
@@ -9,32 +9,44 @@ use onionsalt::{crypto,
                 onionbox_open};
 use std::fmt::{Formatter, Debug};
 use std::fmt;
-use std::io::Error;
+use error::Error;
 use std;
 use super::udp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::str::FromStr;
 use std::sync::mpsc::{ Receiver, Sender, channel,
                        SyncSender, sync_channel, };
-use std::sync::{Arc,Mutex};
+use std::sync::{Arc,Mutex,Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
 
 use message;
+use format;
+use transport::{Transport, UdpTransport};
+use clock;
+use clock::{Clock, SystemClock};
+use rng::{Rng, OsRng};
+use routing;
+use routing::{RoutingTable, Liveness};
+use keystore;
+use secret::SecretKeyPair;
 
 const REPORT_WHOAMIS: bool = false;
 
-fn key_distance(a: &crypto::PublicKey, b: &crypto::PublicKey) -> u64 {
-    let mut out = 0;
-    for i in 0..8 {
-        out += ((a.0[i] ^ b.0[i]) as u64) << (i*8);
-    }
-    out
-}
-
 pub trait MyBytes<T> {
     fn bytes(&self, &mut T);
     fn from_bytes(&T) -> Self;
 }
 
+/// Like `MyBytes`, but for types such as messages where not every
+/// sequence of bytes is valid, so decoding can fail.  This is what we
+/// use for anything that arrives off the network, so that a malformed
+/// packet is an error rather than a panic.
+pub trait FallibleBytes<T> {
+    fn bytes(&self, &mut T);
+    fn from_bytes(&T) -> Result<Self, Error>;
+}
+
 impl MyBytes<[u8; 18]> for SocketAddr {
     fn bytes(&self, out: &mut[u8; 18]) {
         match *self {
@@ -164,6 +176,10 @@ impl MyBytes<[u8; ROUTING_LENGTH]> for RoutingInfo {
 /// key and the authentication overhead.
 pub const NUM_IN_RESPONSE: usize = 10;
 
+/// Each message is deposited at this many of the nodes closest to its
+/// destination, so that it survives some of them going away.
+pub const NUM_RENDEZVOUS: usize = 3;
+
 
 /// The `USER_MESSAGE_LENGTH` is the size of actual content that can
 /// be encrypted and authenticated to send to some receiver.
@@ -242,7 +258,7 @@ pub enum Message {
     },
 }
 
-impl MyBytes<[u8; PAYLOAD_LENGTH]> for Message {
+impl FallibleBytes<[u8; PAYLOAD_LENGTH]> for Message {
     fn bytes(&self, out: &mut[u8; PAYLOAD_LENGTH]) {
         match *self {
             Message::Greetings(gifts) => {
@@ -266,21 +282,21 @@ impl MyBytes<[u8; PAYLOAD_LENGTH]> for Message {
             },
         }
     }
-    fn from_bytes(inp: &[u8; PAYLOAD_LENGTH]) -> Message {
+    fn from_bytes(inp: &[u8; PAYLOAD_LENGTH]) -> Result<Message, Error> {
         match inp[0] {
-            b'g' => Message::Greetings(RoutingGifts::from_bytes(array_ref![inp,1,500])),
-            b'r' => Message::Response(RoutingGifts::from_bytes(array_ref![inp,1,500])),
+            b'g' => Ok(Message::Greetings(RoutingGifts::from_bytes(array_ref![inp,1,500]))),
+            b'r' => Ok(Message::Response(RoutingGifts::from_bytes(array_ref![inp,1,500]))),
             b'p' => {
                 let (_,d,m) = array_refs![inp,1,32,511];
                 let destination = crypto::PublicKey::from_bytes(d);
-                Message::PickUp{ destination: destination, message: *m }
+                Ok(Message::PickUp{ destination: destination, message: *m })
             },
             b'f' => {
                 let (_,d,m) = array_refs![inp,1,32,511];
                 let destination = crypto::PublicKey::from_bytes(d);
-                Message::ForwardPlease{ destination: destination, message: *m }
+                Ok(Message::ForwardPlease{ destination: destination, message: *m })
             },
-            _ => unimplemented!(),
+            _ => Err(Error::MalformedPacket),
         }
     }
 }
@@ -293,7 +309,11 @@ pub struct EncryptedMessage {
 
 impl RoutingInfo {
     pub fn new(saddr: SocketAddr, delay_time: u32) -> RoutingInfo {
-        let eta = (udp::now_ms()/1000+1) as u32 + delay_time;
+        RoutingInfo::new_at(saddr, delay_time, udp::now_ms())
+    }
+    /// Like `new`, but for a packet sent at `now_ms`.
+    pub fn new_at(saddr: SocketAddr, delay_time: u32, now_ms: u64) -> RoutingInfo {
+        let eta = (now_ms/1000+1) as u32 + delay_time;
         RoutingInfo {
             ip: saddr,
             eta: eta,
@@ -303,26 +323,11 @@ impl RoutingInfo {
     }
 }
 
-pub fn read_keypair(name: &std::path::Path) -> Result<crypto::KeyPair, Error> {
-    use std::io::Read;
-
-    let mut f = try!(std::fs::File::open(name));
-    let mut data = Vec::new();
-    try!(f.read_to_end(&mut data));
-    if data.len() != 64 {
-        return Err(Error::new(std::io::ErrorKind::Other, "oh no!"));
-    }
-    Ok(crypto::KeyPair {
-        public: crypto::PublicKey(*array_ref![data, 0, 32]),
-        secret: crypto::SecretKey(*array_ref![data, 32, 32]),
-    })
-}
-
 /// This is just a crude guess as to the hostname.  I didn't put much
 /// effort into this because it is only really relevant in the case
 /// where you have a shared home directory for multiple different
 /// computers that should be independently running pmail.
-pub fn gethostname() -> Result<String, Error> {
+pub fn gethostname() -> Result<String, std::io::Error> {
     use std::io::Read;
 
     let mut f = try!(std::fs::File::open("/etc/hostname"));
@@ -330,34 +335,33 @@ pub fn gethostname() -> Result<String, Error> {
     try!(f.read_to_string(&mut hostname));
     match hostname.split_whitespace().next() {
         Some(hn) => Ok(String::from(hn)),
-        None => Err(Error::new(std::io::ErrorKind::Other, "malformed /etc/hostname")),
+        None => Err(std::io::Error::new(std::io::ErrorKind::Other, "malformed /etc/hostname")),
     }
 }
 
-pub fn read_or_generate_keypair(orig_name: std::path::PathBuf)
-                                -> Result<crypto::KeyPair, Error> {
-    use std::io::Write;
-
+/// Read the keypair in `orig_name`, encrypted with `passphrase`, or
+/// generate one if there is no such file.  We never overwrite a key
+/// file that we cannot read, since it may just be the wrong
+/// passphrase.
+pub fn read_or_generate_keypair(orig_name: std::path::PathBuf, passphrase: &str)
+                                -> Result<SecretKeyPair, Error> {
     let name = orig_name.as_path();
-    match read_keypair(name) {
+    match keystore::read_keypair(name, passphrase) {
         Ok(kp) => {
-            info!("Key {:?} {:?}", &orig_name.as_path(), kp);
+            info!("Key {:?} {:?}", &orig_name.as_path(), kp.public());
             Ok(kp)
         },
-        _ => {
-            let kp = crypto::box_keypair();
-            let mut f = try!(std::fs::File::create(name));
-            let mut data = [0; 64];
-            *array_mut_ref![data, 0, 32] = kp.public.0;
-            *array_mut_ref![data, 32, 32] = kp.secret.0;
-            try!(f.write_all(&data));
+        Err(Error::Storage(ref e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            let kp = SecretKeyPair::generate();
+            try!(keystore::write_keypair(name, &kp, passphrase));
             info!("Created new key!  [");
             for i in 0..32 {
-                info!("{}, ", kp.public.0[i]);
+                info!("{}, ", kp.public().0[i]);
             }
             info!("]");
             Ok(kp)
-        }
+        },
+        Err(e) => Err(e),
     }
 }
 
@@ -388,6 +392,112 @@ fn wentworth() -> RoutingGift {
     RoutingGift { addr: addr, key: key }
 }
 
+/// The public nodes that we contact by default in order to join the
+/// network.
+pub fn default_bootstrap() -> Vec<RoutingGift> {
+    vec![bingley(), knightley(), wentworth()]
+}
+
+/// How a node joins the network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeConfig {
+    /// The nodes to contact when we start up.
+    pub bootstrap: Vec<RoutingGift>,
+    /// Whether to also contact the nodes in `default_bootstrap`.
+    pub use_default_bootstrap: bool,
+    /// Where to listen for packets.
+    pub listen: udp::ListenConfig,
+    /// How often we send a packet.
+    pub send_period_ms: u64,
+    /// Where to save our routing table, so that we can pick up where
+    /// we left off after a restart.
+    pub state_dir: Option<std::path::PathBuf>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig {
+            bootstrap: Vec::new(),
+            use_default_bootstrap: true,
+            listen: udp::ListenConfig::default(),
+            send_period_ms: 1000*10,
+            state_dir: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    fn bootstrap_name(the_dir: &std::path::PathBuf) -> std::path::PathBuf {
+        let mut name = the_dir.clone();
+        name.push("bootstrap");
+        name
+    }
+    /// Read the configuration from the `bootstrap` file in `the_dir`,
+    /// falling back to the default configuration if there is none.
+    /// The file only holds the bootstrap nodes, so we listen wherever
+    /// `udp::ListenConfig::default()` says.
+    pub fn read(the_dir: &std::path::PathBuf) -> Result<NodeConfig, Error> {
+        let name = NodeConfig::bootstrap_name(the_dir);
+        if std::fs::metadata(&name).is_err() {
+            return Ok(NodeConfig::default());
+        }
+        let b: format::Bootstrap = try!(format::read_json(&name));
+        let mut config = NodeConfig {
+            use_default_bootstrap: b.use_default_nodes,
+            .. NodeConfig::default()
+        };
+        for n in b.nodes {
+            match SocketAddr::from_str(&n.addr) {
+                Ok(addr) => config.bootstrap.push(RoutingGift { addr: addr, key: n.key }),
+                Err(_) => {
+                    return Err(Error::Storage(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("bad bootstrap address {}", n.addr))));
+                },
+            }
+        }
+        Ok(config)
+    }
+    /// Save the configuration in the `bootstrap` file in `the_dir`.
+    pub fn write(&self, the_dir: &std::path::PathBuf) -> Result<(), Error> {
+        let b = format::Bootstrap {
+            use_default_nodes: self.use_default_bootstrap,
+            nodes: self.bootstrap.iter().map(|g| {
+                format::BootstrapNode { addr: format!("{}", g.addr), key: g.key }
+            }).collect(),
+        };
+        try!(format::write_json(NodeConfig::bootstrap_name(the_dir), &b));
+        Ok(())
+    }
+    /// All the nodes we should contact when we start up.
+    pub fn nodes(&self) -> Vec<RoutingGift> {
+        let mut out = self.bootstrap.clone();
+        if self.use_default_bootstrap {
+            out.extend(default_bootstrap());
+        }
+        out
+    }
+}
+
+#[test]
+fn test_node_config() {
+    let dir = format::test_dir();
+    assert_eq!(NodeConfig::read(&dir).unwrap(), NodeConfig::default());
+    assert_eq!(NodeConfig::default().nodes(), default_bootstrap());
+    let config = NodeConfig {
+        bootstrap: vec![RoutingGift { addr: SocketAddr::from_str("127.0.0.1:5000").unwrap(),
+                                      key: crypto::box_keypair().public },
+                        RoutingGift { addr: SocketAddr::from_str("[::1]:5001").unwrap(),
+                                      key: crypto::box_keypair().public }],
+        use_default_bootstrap: false,
+        .. NodeConfig::default()
+    };
+    config.write(&dir).unwrap();
+    let config2 = NodeConfig::read(&dir).unwrap();
+    assert_eq!(config2, config);
+    assert_eq!(config2.nodes(), config.bootstrap);
+}
+
 pub fn codename(text: &[u8]) -> String {
     let long_version = false;
     let adjectives = ["good", "happy", "nice", "evil", "sloppy", "slovenly",
@@ -432,25 +542,178 @@ struct ScheduledTransmission {
 struct SentMsg {
     ob: onionsalt::OnionBox,
     who_relayed: [crypto::PublicKey; ROUTE_COUNT],
+    /// When we give up on hearing a response.
+    expires_ms: u64,
+}
+
+/// Counts of the entries we have thrown away because their
+/// counterpart never arrived, which tell us how often routes fail.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExpiryStats {
+    /// Onions we sent that were never answered.
+    pub onions: u64,
+    /// Pickup requests for which no message arrived.
+    pub pickups: u64,
+    /// Messages that were never picked up.
+    pub messages: u64,
+    /// Pickup requests dropped to make room for newer ones.
+    pub displaced_pickups: u64,
+}
+
+/// Counts of what happened to packets that did not fit in the `timer`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScheduleStats {
+    /// Packets put in the overflow queue to be sent late.
+    pub overflowed: u64,
+    /// Packets dropped because the overflow queue was full.
+    pub dropped_full: u64,
+    /// Packets that were only worth sending if convenient, and were
+    /// not.
+    pub dropped_inconvenient: u64,
 }
 
 const TIMER_WINDOW: usize = 60*6; // one hour?
 const MAX_LIVENESS: u8 = (ROUTE_COUNT as u8);
+/// The most liveness we credit a node with when reloading it from the
+/// `peers` file, so that nodes which went away while we were not
+/// running are soon found out.
+const RESTORED_LIVENESS: u8 = 2;
+/// How many send periods pass between saves of the `peers` file.
+const SAVE_PEERS_PERIODS: usize = 6;
+/// How many send periods pass between sweeps for expired entries.
+const EXPIRE_PERIODS: usize = 6;
+/// The most scheduled packets we send when shutting down.  They are
+/// paced like any others, so this bounds how long shutting down
+/// takes; the rest are dropped, and will be resent by their senders.
+const MAX_FLUSHED: usize = 6;
+/// The most packets we hold in the overflow queue when the `timer` is
+/// full.  Beyond this we drop those with the latest eta.
+const MAX_OVERFLOW: usize = TIMER_WINDOW;
+/// The most messages we hold for any one destination.  Since every
+/// message is the same size, this is also a quota on their storage.
+const MAX_QUEUED_MESSAGES: usize = 32;
+/// How long past its eta we hold a message that nobody has picked up.
+/// Unpicked messages will be resent by their sender anyhow.
+const MESSAGE_TTL_MS: u64 = 3*24*60*60*1000;
+/// The most pickup requests we hold for any one destination.
+const MAX_QUEUED_PICKUPS: usize = 8;
+/// How long past its eta a pickup request remains useful for, after
+/// which its route back to the user has probably gone stale.
+const PICKUP_TTL_MS: u64 = 10*60*1000;
+
+/// Something held at a rendezvous until its counterpart arrives.
+struct Pending<T> {
+    expires_ms: u64,
+    item: T,
+}
+
+/// Discard the expired entries of `q`, returning how many there were.
+fn expire<T>(q: &mut VecDeque<Pending<T>>, now: u64) -> u64 {
+    let mut expired = 0;
+    for _ in 0 .. q.len() {
+        if let Some(p) = q.pop_front() {
+            if p.expires_ms > now {
+                q.push_back(p);
+            } else {
+                expired += 1;
+            }
+        }
+    }
+    expired
+}
+
+/// Discard the expired entries of every queue in `queues`, returning
+/// how many there were.
+fn expire_all<T>(queues: &mut HashMap<crypto::PublicKey, VecDeque<Pending<T>>>,
+                 now: u64) -> u64 {
+    let mut expired = 0;
+    let mut empty = Vec::new();
+    for (k, q) in queues.iter_mut() {
+        expired += expire(q, now);
+        if q.is_empty() {
+            empty.push(*k);
+        }
+    }
+    for k in empty {
+        queues.remove(&k);
+    }
+    expired
+}
+
+/// Take the oldest unexpired entry for `k`, adding the number of
+/// expired entries to `expired`.
+fn take_pending<T>(queues: &mut HashMap<crypto::PublicKey, VecDeque<Pending<T>>>,
+                   k: &crypto::PublicKey, now: u64, expired: &mut u64) -> Option<T> {
+    let (out, empty) = match queues.get_mut(k) {
+        None => { return None; },
+        Some(q) => {
+            *expired += expire(q, now);
+            let out = q.pop_front().map(|p| { p.item });
+            (out, q.is_empty())
+        },
+    };
+    if empty {
+        queues.remove(k);
+    }
+    out
+}
+
+fn peers_name(the_dir: &std::path::PathBuf) -> std::path::PathBuf {
+    let mut name = the_dir.clone();
+    name.push("peers");
+    name
+}
+
+/// Read the routing table saved in `the_dir`, along with the
+/// liveness of each node.
+fn read_peers(the_dir: &std::path::PathBuf) -> Vec<(RoutingGift, u8)> {
+    let peers: Vec<format::KnownPeer> = match format::read_json(peers_name(the_dir)) {
+        Ok(p) => p,
+        Err(_) => { return Vec::new(); },
+    };
+    let mut out = Vec::new();
+    for p in peers {
+        match SocketAddr::from_str(&p.addr) {
+            Ok(addr) => out.push((RoutingGift { addr: addr, key: p.key }, p.liveness)),
+            Err(_) => info!("Ignoring saved peer with bad address {}", p.addr),
+        }
+    }
+    out
+}
+
+fn write_peers(the_dir: &std::path::PathBuf, peers: &[(RoutingGift, u8)]) -> Result<(), Error> {
+    let peers: Vec<format::KnownPeer> = peers.iter().map(|&(g, liveness)| {
+        format::KnownPeer { addr: format!("{}", g.addr), key: g.key, liveness: liveness }
+    }).collect();
+    try!(format::write_json(peers_name(the_dir), &peers));
+    Ok(())
+}
 
 struct DHT {
     newbies: HashSet<crypto::PublicKey>,
-    addresses: HashMap<crypto::PublicKey, SocketAddr>,
+    addresses: RoutingTable,
     pubkeys: HashMap<SocketAddr, crypto::PublicKey>,
-    liveness: HashMap<crypto::PublicKey, u8>,
-    old_liveness: HashMap<crypto::PublicKey, u8>,
-    to_forward: HashMap<crypto::PublicKey, onionsalt::OpenedOnionBox>,
-    to_pickup: HashMap<crypto::PublicKey, Message>,
-    my_key: crypto::KeyPair,
+    liveness: Liveness,
+    old_liveness: Liveness,
+    /// Pickup requests waiting for a message, oldest first.
+    to_forward: HashMap<crypto::PublicKey, VecDeque<Pending<onionsalt::OpenedOnionBox>>>,
+    /// Messages waiting for a pickup request, oldest first.
+    to_pickup: HashMap<crypto::PublicKey, VecDeque<Pending<[u8; USER_MESSAGE_LENGTH]>>>,
+    my_key: Arc<SecretKeyPair>,
     timer: [Option<ScheduledTransmission>; TIMER_WINDOW],
+    /// Packets that did not fit in `timer`, keyed by eta and then
+    /// arrival order.  These are sent, earliest eta first, in place
+    /// of cover traffic.
+    overflow: BTreeMap<(u64, u64), udp::RawEncryptedMessage>,
+    overflow_seq: u64,
+    scheduling: ScheduleStats,
     /// When we send messages, we should store their OnionBoxen in this
     /// map, so we can listen for the return...
     onionboxen: HashMap<[u8; 32], SentMsg>,
     send_period_ms: u64,
+    clock: Arc<Clock>,
+    rng: Box<Rng>,
+    expired: ExpiryStats,
 }
 
 trait WithLock {
@@ -473,41 +736,190 @@ impl WithLock for Arc<Mutex<DHT>> {
 }
 
 impl DHT {
-    fn new(myself: &crypto::KeyPair, send_period_ms: u64,) -> Arc<Mutex<DHT>> {
+    fn new(myself: Arc<SecretKeyPair>, send_period_ms: u64,
+           bootstrap: &[RoutingGift], known: &[(RoutingGift, u8)],
+           clock: Arc<Clock>, rng: Box<Rng>) -> Arc<Mutex<DHT>> {
         let dht = Arc::new(Mutex::new(DHT {
             newbies: HashSet::new(),
-            addresses: HashMap::new(),
+            addresses: RoutingTable::new(myself.public()),
             pubkeys: HashMap::new(),
             onionboxen: HashMap::new(),
             to_forward: HashMap::new(),
             to_pickup: HashMap::new(),
-            liveness: HashMap::new(),
-            old_liveness: HashMap::new(),
-            my_key: *myself,
+            liveness: Liveness::new(),
+            old_liveness: Liveness::new(),
+            my_key: myself,
             timer: [None; TIMER_WINDOW],
+            overflow: BTreeMap::new(),
+            overflow_seq: 0,
+            scheduling: ScheduleStats::default(),
             send_period_ms: send_period_ms,
+            clock: clock,
+            rng: rng,
+            expired: ExpiryStats::default(),
         }));
         // initialize a the mappings!
-        dht.with_lock(|dht| { dht.accept_single_gift(&bingley()) });
-        dht.with_lock(|dht| { dht.accept_single_gift(&knightley()) });
-        dht.with_lock(|dht| { dht.accept_single_gift(&wentworth()) });
+        for g in bootstrap {
+            dht.with_lock(|dht| { dht.accept_single_gift(g) });
+        }
+        for &(ref g, liveness) in known {
+            dht.with_lock(|dht| { dht.accept_known_peer(g, liveness) });
+        }
         dht
     }
+    /// Add a node that we knew about before a restart.  We trust it
+    /// less than we used to, since it may have gone away meanwhile.
+    fn accept_known_peer(&mut self, g: &RoutingGift, liveness: u8) {
+        if g.key == self.my_key.public() {
+            // Our own address may well have changed.
+            return;
+        }
+        self.accept_single_gift(g);
+        let liveness = std::cmp::min(liveness, RESTORED_LIVENESS);
+        if liveness > 0 && self.addresses.get(&g.key) == Some(&g.addr) {
+            self.liveness.insert(g.key, liveness);
+            self.newbies.remove(&g.key);
+        }
+    }
+    /// The nodes we know of, other than ourselves, along with their
+    /// liveness, for saving in the `peers` file.
+    fn known_peers(&self) -> Vec<(RoutingGift, u8)> {
+        let mut out = Vec::new();
+        for k in self.addresses.keys() {
+            if k != self.my_key.public() {
+                let liveness = *self.liveness.get(&k).unwrap_or(&0);
+                if let Some(&addr) = self.addresses.get(&k) {
+                    out.push((RoutingGift { key: k, addr: addr }, liveness));
+                }
+            }
+        }
+        out
+    }
+    /// The `NUM_RENDEZVOUS` nodes closest to `k`, closest first.  We
+    /// may be one of them ourselves.
+    fn rendezvous_nodes(&self, k: &crypto::PublicKey) -> Vec<crypto::PublicKey> {
+        let mut out: Vec<_> = self.addresses.closest(k, NUM_RENDEZVOUS).iter().map(|&(kk, _)| { kk }).collect();
+        out.push(self.my_key.public());
+        out.sort_by(|a, b| { routing::distance(a, k).cmp(&routing::distance(b, k)) });
+        out.truncate(NUM_RENDEZVOUS);
+        out
+    }
     fn construct_gift(&mut self) -> [RoutingGift; NUM_IN_RESPONSE] {
-        let mut out = [bingley(); NUM_IN_RESPONSE];
-        for i in 0..NUM_IN_RESPONSE {
+        let mut out = [self.random_live_gift(); NUM_IN_RESPONSE];
+        for i in 1..NUM_IN_RESPONSE {
             out[i] = self.random_live_gift();
         }
         out
     }
     fn accept_single_gift(&mut self, g: &RoutingGift) {
         if !self.addresses.contains_key(&g.key) {
-            self.addresses.insert(g.key, g.addr);
+            match self.addresses.insert(g.key, g.addr, &self.liveness) {
+                Some((k, _)) if k == g.key => {
+                    // Its bucket is full of nodes that are relaying
+                    // for us, which we would rather keep.
+                    return;
+                },
+                Some((old, old_addr)) => {
+                    // Its bucket was full, so we forget the node that
+                    // we have gone longest without hearing from.
+                    self.forget(&old, &old_addr);
+                },
+                None => (),
+            }
             self.pubkeys.insert(g.addr, g.key);
             self.newbies.insert(g.key);
             self.print("got gift");
         }
     }
+    /// Note that `k` relayed for us, unless we have evicted it
+    /// meanwhile, in which case we no longer count on it.
+    fn mark_live(&mut self, k: &crypto::PublicKey) {
+        if self.addresses.contains_key(k) {
+            self.liveness.insert(*k, MAX_LIVENESS);
+            self.addresses.touch(k);
+            self.newbies.remove(k);
+        }
+    }
+    /// Forget everything about `k`, which has already been evicted
+    /// from `addresses`, and was at `addr`.
+    fn forget(&mut self, k: &crypto::PublicKey, addr: &SocketAddr) {
+        if self.pubkeys.get(addr) == Some(k) {
+            self.pubkeys.remove(addr);
+        }
+        self.liveness.remove(k);
+        self.old_liveness.remove(k);
+        self.newbies.remove(k);
+    }
+    /// Hold `message`, which arrived with routing `eta`, until
+    /// `destination` asks for it.  Returns false if `destination` has
+    /// used up its quota.
+    fn queue_message(&mut self, destination: crypto::PublicKey, eta: u32,
+                     message: [u8; USER_MESSAGE_LENGTH]) -> bool {
+        let now = self.clock.now_ms();
+        let expires_ms = self.expiry(eta, MESSAGE_TTL_MS);
+        let q = self.to_pickup.entry(destination).or_insert(VecDeque::new());
+        self.expired.messages += expire(q, now);
+        if q.len() >= MAX_QUEUED_MESSAGES {
+            return false;
+        }
+        q.push_back(Pending { expires_ms: expires_ms, item: message });
+        true
+    }
+    fn take_message(&mut self, destination: &crypto::PublicKey)
+                    -> Option<[u8; USER_MESSAGE_LENGTH]> {
+        let now = self.clock.now_ms();
+        take_pending(&mut self.to_pickup, destination, now, &mut self.expired.messages)
+    }
+    /// Hold a pickup request, which arrived with routing `eta`, until a
+    /// message for `destination` arrives.  If there are too many
+    /// already, we drop the oldest.
+    fn queue_pickup(&mut self, destination: crypto::PublicKey, eta: u32,
+                    oob: onionsalt::OpenedOnionBox) {
+        let now = self.clock.now_ms();
+        let expires_ms = self.expiry(eta, PICKUP_TTL_MS);
+        let q = self.to_forward.entry(destination).or_insert(VecDeque::new());
+        self.expired.pickups += expire(q, now);
+        if q.len() >= MAX_QUEUED_PICKUPS {
+            q.pop_front();
+            self.expired.displaced_pickups += 1;
+        }
+        q.push_back(Pending { expires_ms: expires_ms, item: oob });
+    }
+    fn take_pickup(&mut self, destination: &crypto::PublicKey)
+                   -> Option<onionsalt::OpenedOnionBox> {
+        let now = self.clock.now_ms();
+        take_pending(&mut self.to_forward, destination, now, &mut self.expired.pickups)
+    }
+    /// When to give up on something that arrived with routing `eta`,
+    /// `ttl_ms` after it was due.  The `eta` comes from whoever built
+    /// the route, so we believe it only as far ahead as our own
+    /// schedule reaches, lest they make us hold onto it forever.
+    fn expiry(&self, eta: u32, ttl_ms: u64) -> u64 {
+        let latest = self.clock.now_ms() + TIMER_WINDOW as u64*self.send_period_ms;
+        std::cmp::min(eta as u64*1000, latest) + ttl_ms
+    }
+    /// When to give up on a response to an onion whose last hop has
+    /// routing `eta`.  We allow for it being held up by a full timer
+    /// at each end.
+    fn onion_expiry(&self, eta: u32) -> u64 {
+        eta as u64*1000 + 2*TIMER_WINDOW as u64*self.send_period_ms
+    }
+    /// Throw away everything that has waited too long for its
+    /// counterpart.  Returns true if anything was thrown away.
+    fn expire_stale(&mut self) -> bool {
+        let before = self.expired;
+        let now = self.clock.now_ms();
+        let stale: Vec<[u8; 32]> = self.onionboxen.iter()
+            .filter(|&(_, sm)| { sm.expires_ms <= now })
+            .map(|(k, _)| { *k }).collect();
+        for k in stale {
+            self.onionboxen.remove(&k);
+            self.expired.onions += 1;
+        }
+        self.expired.pickups += expire_all(&mut self.to_forward, now);
+        self.expired.messages += expire_all(&mut self.to_pickup, now);
+        self.expired != before
+    }
     fn accept_gift(&mut self, gift: &[RoutingGift; NUM_IN_RESPONSE]) {
         for g in gift {
             self.accept_single_gift(g);
@@ -515,40 +927,43 @@ impl DHT {
     }
     fn random_key(&mut self) -> crypto::PublicKey {
         let i = self.random_usize() % self.addresses.len();
-        let mut keys = self.addresses.keys();
-        *keys.nth(i).unwrap()
+        self.addresses.nth(i).unwrap()
     }
     fn random_live_key(&mut self) -> crypto::PublicKey {
         let len = self.liveness.len();
         if len == 0 {
-            if self.addresses.contains_key(&self.my_key.public) {
-                return self.my_key.public;
+            if self.addresses.contains_key(&self.my_key.public()) {
+                return self.my_key.public();
             }
-            return bingley().key;
+            return self.random_key();
         }
         let i = self.random_usize() % len;
-        let mut keys = self.liveness.keys();
-        *keys.nth(i).unwrap()
+        self.liveness.nth(i).unwrap()
     }
     fn random_gift(&mut self) -> RoutingGift {
         let k = self.random_key();
+        // random_key only picks from addresses, so this is there.
         RoutingGift { key: k, addr: self.addresses[&k] }
     }
     fn random_live_gift(&mut self) -> RoutingGift {
         let k = self.random_live_key();
-        RoutingGift { key: k, addr: self.addresses[&k] }
+        match self.addresses.get(&k) {
+            Some(&addr) => RoutingGift { key: k, addr: addr },
+            None => {
+                // We have evicted it since it was last live.
+                self.liveness.remove(&k);
+                self.random_gift()
+            },
+        }
     }
     fn random_usize(&mut self) -> usize {
         self.random_u32() as usize
     }
     fn random_u64(&mut self) -> u64 {
-        self.random_u32() as u64
+        self.rng.next_u64()
     }
     fn random_u32(&mut self) -> u32 {
-        // The following is a really stupid way of getting a random
-        // usize so that we will send to a random node each time.
-        let r = crypto::random_nonce().0;
-        r[0] as u32 + ((r[1] as u32)<<8) + ((r[2] as u32)<<16)
+        self.rng.next_u32()
     }
     fn pick_live_route(&mut self) -> Vec<RoutingGift> {
         let len = self.liveness.len();
@@ -556,11 +971,11 @@ impl DHT {
         let mut out = Vec::new();
         for _ in 0 .. 3 + (self.random_usize() % 4) {
             let mut new_gift = self.random_live_gift();
-            if out.len() > 1 && (out.contains(&new_gift) || new_gift.key == self.my_key.public) {
+            if out.len() > 1 && (out.contains(&new_gift) || new_gift.key == self.my_key.public()) {
                 // Let's not create a loop that loops back on itself.
                 return out;
             }
-            while new_gift.key == self.my_key.public || out.contains(&new_gift) {
+            while new_gift.key == self.my_key.public() || out.contains(&new_gift) {
                 new_gift = self.random_live_gift();
             }
             out.push(new_gift);
@@ -572,11 +987,11 @@ impl DHT {
         let mut out = Vec::new();
         for _ in 0 .. 3 + (self.random_usize() % 4) {
             let mut new_gift = self.random_gift();
-            if out.len() > 1 && (out.contains(&new_gift) || new_gift.key == self.my_key.public) {
+            if out.len() > 1 && (out.contains(&new_gift) || new_gift.key == self.my_key.public()) {
                 // Let's not create a loop that loops back on itself.
                 return out;
             }
-            while new_gift.key == self.my_key.public || out.contains(&new_gift) {
+            while new_gift.key == self.my_key.public() || out.contains(&new_gift) {
                 new_gift = self.random_gift();
             }
             out.push(new_gift);
@@ -584,14 +999,62 @@ impl DHT {
         out
     }
     fn schedule_if_convenient(&mut self, eta: u32, msg: &udp::RawEncryptedMessage) {
-        self.schedule_internal(eta, msg, 1); // this number is an arbitrary sloppiness
+        if !self.schedule_internal(eta, msg, 1) { // this number is an arbitrary sloppiness
+            self.scheduling.dropped_inconvenient += 1;
+        }
     }
     fn schedule(&mut self, eta: u32, msg: &udp::RawEncryptedMessage) {
-        self.schedule_internal(eta, msg, TIMER_WINDOW as u64);
+        if !self.schedule_internal(eta, msg, TIMER_WINDOW as u64) {
+            self.schedule_overflow(eta as u64 * 1000, msg);
+        }
     }
-    fn schedule_internal(&mut self, eta: u32, msg: &udp::RawEncryptedMessage, steadfastness: u64) {
+    /// Queue a packet that did not fit in the `timer`.  When the queue
+    /// is full we drop whichever packet has the latest eta, since it
+    /// is the one we would send last anyhow.
+    fn schedule_overflow(&mut self, eta: u64, msg: &udp::RawEncryptedMessage) {
+        self.overflow_seq += 1;
+        self.overflow.insert((eta, self.overflow_seq), *msg);
+        self.scheduling.overflowed += 1;
+        if self.overflow.len() > MAX_OVERFLOW {
+            let last = *self.overflow.keys().next_back().unwrap();
+            self.overflow.remove(&last);
+            self.scheduling.dropped_full += 1;
+        }
+    }
+    fn status(&self) -> NodeStatus {
+        NodeStatus {
+            my_addr: self.addresses.get(&self.my_key.public()).cloned(),
+            known_peers: self.known_peers().len(),
+            live_peers: self.liveness.keys().filter(|&k| { *k != self.my_key.public() }).count(),
+            queue_depth: self.queue_depth(),
+            waiting_messages: self.to_pickup.values().fold(0, |n, q| { n + q.len() }),
+            waiting_pickups: self.to_forward.values().fold(0, |n, q| { n + q.len() }),
+            expired: self.expired,
+            scheduling: self.scheduling,
+        }
+    }
+    /// Take every packet we have scheduled, earliest first.
+    fn drain_scheduled(&mut self) -> Vec<udp::RawEncryptedMessage> {
+        let mut out = Vec::new();
+        for t in self.timer.iter_mut() {
+            if let Some(sch) = t.take() {
+                out.push((sch.eta, sch.msg));
+            }
+        }
+        let overflow = std::mem::replace(&mut self.overflow, BTreeMap::new());
+        out.extend(overflow.into_iter().map(|((eta, _), m)| { (eta, m) }));
+        out.sort_by(|a, b| { a.0.cmp(&b.0) });
+        out.into_iter().map(|(_, m)| { m }).collect()
+    }
+    /// The number of packets waiting to be sent, not counting cover
+    /// traffic.
+    fn queue_depth(&self) -> usize {
+        self.timer.iter().filter(|t| { t.is_some() }).count() + self.overflow.len()
+    }
+    /// Returns false if there was no free slot for `msg`.
+    fn schedule_internal(&mut self, eta: u32, msg: &udp::RawEncryptedMessage, steadfastness: u64) -> bool {
         let eta = eta as u64 * 1000; // convert to ms!
-        let n = udp::now_ms();
+        let n = self.clock.now_ms();
         let mut idx = (n+1)/self.send_period_ms + 1;
         if (eta as i64 - n as i64)/self.send_period_ms as i64 > 0 {
             idx += self.random_u64() % ((eta-n)/self.send_period_ms);
@@ -602,43 +1065,45 @@ impl DHT {
                 self.timer[idx as usize % TIMER_WINDOW] =
                     Some(ScheduledTransmission { eta: eta,
                                                  msg: *msg});
-                return;
+                return true;
             }
         }
+        false
     }
-    fn msg(&mut self, idx: usize) -> udp::RawEncryptedMessage {
+    /// The message to send at time slot `idx`, which is `None` only
+    /// if we know of no other node to talk to.
+    fn msg(&mut self, idx: usize) -> Option<udp::RawEncryptedMessage> {
         match self.timer[idx % TIMER_WINDOW] {
             Some(sch) => {
                 self.timer[idx % TIMER_WINDOW] = None;
-                sch.msg
+                Some(sch.msg)
             },
             None => {
-                let (addr,sm) = self.maintenance();
+                let first = self.overflow.keys().next().cloned();
+                if let Some(k) = first {
+                    // Overflowed packets take the place of cover
+                    // traffic, so we still send at a constant rate.
+                    return self.overflow.remove(&k);
+                }
+                let (addr,sm) = match self.maintenance() {
+                    Some(x) => x,
+                    None => { return None; },
+                };
                 let msg = udp::RawEncryptedMessage {
                     ip: addr,
                     data: sm.ob.packet(),
                 };
                 for i in 0 .. ROUTE_COUNT {
                     let k = sm.who_relayed[i];
-                    if k != self.my_key.public {
-                        let deleteme = match self.liveness.get_mut(&k) {
-                            None => false,
-                            Some(liveness) => {
-                                *liveness -= 1;
-                                *liveness == 0
-                            },
-                        };
-                        if deleteme {
-                            self.liveness.remove(&k);
-                            self.newbies.insert(k);
-                        }
+                    if k != self.my_key.public() && self.liveness.decrement(&k) {
+                        self.newbies.insert(k);
                     }
                 }
                 self.print("changed liveness");
                 // The following enables us to easily check for a response
                 // to this message.
                 self.onionboxen.insert(sm.ob.return_magic(), sm);
-                msg
+                Some(msg)
             }
         }
     }
@@ -649,13 +1114,14 @@ impl DHT {
         let route = self.pick_route();
         let mut recipient = self.random_usize() % route.len();
         // avoid sending greetings to myself!
-        while route[recipient].key == self.my_key.public {
+        while route[recipient].key == self.my_key.public() {
             recipient = self.random_usize() % route.len();
         }
         // info!("Sending a nice greeting loop of length {}", route.len());
         let mut keys_and_routes = Vec::new();
         let mut delay_time = 0;
-        let mut who_relayed = [self.my_key.public; ROUTE_COUNT];
+        let mut eta = 0;
+        let mut who_relayed = [self.my_key.public(); ROUTE_COUNT];
         for i in 0 .. route.len() {
             who_relayed[i] = route[i].key;
             let mut k_and_r = (route[i].key, [0; ROUTING_LENGTH]);
@@ -663,9 +1129,9 @@ impl DHT {
                 route[i+1].addr
             } else {
                 // the following delivers the response back to us, or
-                // to bingley (uselessly) if we do not yet know our
-                // own address.
-                *self.addresses.get(&self.my_key.public).unwrap_or(&bingley().addr)
+                // to the first node on the route (uselessly) if we do
+                // not yet know our own address.
+                *self.addresses.get(&self.my_key.public()).unwrap_or(&route[0].addr)
             };
             // if i == recipient {
             //     info!(" => {}", route[i].addr);
@@ -674,18 +1140,20 @@ impl DHT {
             // }
             let delay_ms = self.send_period_ms + self.random_u64() % (6*self.send_period_ms);
             delay_time += ((delay_ms+999)/1000) as u32;
-            let mut ri = RoutingInfo::new(next_addr, delay_time);
+            let mut ri = RoutingInfo::new_at(next_addr, delay_time, self.clock.now_ms());
             ri.is_for_me = i == recipient;
             ri.who_am_i = false;
             ri.bytes(&mut k_and_r.1);
             keys_and_routes.push(k_and_r);
+            eta = ri.eta;
         }
 
         let mut ob = onionbox(&keys_and_routes, recipient).unwrap();
-        ob.add_payload(self.my_key, &payload);
+        self.my_key.with_copy(|kp| { ob.add_payload(kp, &payload) });
         // info!("greeting: {} -> ... -> {}",
         //       codename(&ob.packet()), codename(&ob.return_magic()));
-        (route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed })
+        let expires_ms = self.onion_expiry(eta);
+        (route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed, expires_ms: expires_ms })
     }
     fn send_ciphertext(&mut self, rendezvous: crypto::PublicKey,
                        ciphertext: [u8;PAYLOAD_LENGTH], total_delay_ms: u64)
@@ -702,13 +1170,17 @@ impl DHT {
             }
         }
         if route[recipient].key != rendezvous {
+            route[recipient].addr = match self.addresses.get(&rendezvous) {
+                Some(&addr) => addr,
+                None => { return None; },
+            };
             route[recipient].key = rendezvous;
-            route[recipient].addr = self.addresses[&rendezvous];
         }
         // info!("Sending a nice message loop of length {}", route.len());
         let mut keys_and_routes = Vec::new();
         let mut delay_time = 0;
-        let mut who_relayed = [self.my_key.public; ROUTE_COUNT];
+        let mut eta = 0;
+        let mut who_relayed = [self.my_key.public(); ROUTE_COUNT];
         for i in 0 .. route.len() {
             who_relayed[i] = route[i].key;
             let mut k_and_r = (route[i].key, [0; ROUTING_LENGTH]);
@@ -716,9 +1188,9 @@ impl DHT {
                 route[i+1].addr
             } else {
                 // the following delivers the response back to us, or
-                // to bingley (uselessly) if we do not yet know our
-                // own address.
-                *self.addresses.get(&self.my_key.public).unwrap_or(&bingley().addr)
+                // to the first node on the route (uselessly) if we do
+                // not yet know our own address.
+                *self.addresses.get(&self.my_key.public()).unwrap_or(&route[0].addr)
             };
             // if i == recipient {
             //     info!(" => {}", route[i].addr);
@@ -727,17 +1199,19 @@ impl DHT {
             // }
             let delay_ms = self.send_period_ms + self.random_u64() % total_delay_ms;
             delay_time += ((delay_ms+999)/1000) as u32;
-            let mut ri = RoutingInfo::new(next_addr, delay_time);
+            let mut ri = RoutingInfo::new_at(next_addr, delay_time, self.clock.now_ms());
             ri.is_for_me = i == recipient;
             ri.who_am_i = false;
             ri.bytes(&mut k_and_r.1);
             keys_and_routes.push(k_and_r);
+            eta = ri.eta;
         }
         let mut ob = onionbox(&keys_and_routes, recipient).unwrap();
-        ob.add_payload(self.my_key, &ciphertext);
+        self.my_key.with_copy(|kp| { ob.add_payload(kp, &ciphertext) });
         // info!("sending something: {} -> ... -> {}",
         //       codename(&ob.packet()), codename(&ob.return_magic()));
-        Some((route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed }))
+        let expires_ms = self.onion_expiry(eta);
+        Some((route[0].addr, SentMsg { ob: ob, who_relayed: who_relayed, expires_ms: expires_ms }))
     }
     fn whoami(&mut self, who: &RoutingGift) -> (SocketAddr, SentMsg) {
         let mut hello_payload = [0; PAYLOAD_LENGTH];
@@ -748,30 +1222,39 @@ impl DHT {
         // whoami.  This prevents whoami responses from being
         // scheduled in the future, which relies on whoami responses
         // being dropped rather than delayed.
-        let mut ri = RoutingInfo::new(who.addr, 1);
+        let mut ri = RoutingInfo::new_at(who.addr, 1, self.clock.now_ms());
         ri.is_for_me = true;
         ri.who_am_i = true;
         ri.bytes(&mut keys_and_routes[0].1);
 
         let mut ob = onionbox(&keys_and_routes, 0).unwrap();
-        ob.add_payload(self.my_key, &hello_payload);
+        self.my_key.with_copy(|kp| { ob.add_payload(kp, &hello_payload) });
         if REPORT_WHOAMIS {
             info!("whoami: {} -> {} -> {}\n",
                   codename(&ob.packet()), who.addr,
                   codename(&ob.return_magic()));
         }
-        (who.addr, SentMsg { ob: ob, who_relayed: [self.my_key.public; ROUTE_COUNT] })
+        let expires_ms = self.onion_expiry(ri.eta);
+        (who.addr, SentMsg {
+            ob: ob,
+            who_relayed: [self.my_key.public(); ROUTE_COUNT],
+            expires_ms: expires_ms,
+        })
     }
 
-    fn maintenance(&mut self) -> (SocketAddr, SentMsg) {
+    fn maintenance(&mut self) -> Option<(SocketAddr, SentMsg)> {
+        if self.addresses.is_empty() {
+            // We are on our own until some other node contacts us.
+            return None;
+        }
         // We almost always send greetings, because they are the least
         // expensive in terms of use of the network, and the most
         // safely ignored by our recipients.
-        if !self.addresses.contains_key(&self.my_key.public) || self.addresses.len() < 3 || self.random_usize() % ROUTE_COUNT != 0 {
+        if !self.addresses.contains_key(&self.my_key.public()) || self.addresses.len() < 3 || self.random_usize() % ROUTE_COUNT != 0 {
             let gift = self.random_gift();
-            return self.whoami(&gift);
+            return Some(self.whoami(&gift));
         }
-        self.greet()
+        Some(self.greet())
     }
     fn print(&mut self, _note: &str) {
         if self.old_liveness != self.liveness {
@@ -792,12 +1275,9 @@ impl DHT {
 }
 
 /// Start relaying messages with a static public key (i.e. one that
-/// does not change).
-pub fn start_static_node(the_dir: &std::path::PathBuf)
-                         -> Result<(SyncSender<crypto::PublicKey>,
-                                    Receiver<crypto::PublicKey>,
-                                    Sender<EncryptedMessage>,
-                                    Receiver<UserMessage>), Error> {
+/// does not change), joining the network via the nodes in `config`.
+pub fn start_static_node(the_dir: &std::path::PathBuf, config: &NodeConfig, passphrase: &str)
+                         -> Result<Node, Error> {
     let my_key = {
         let mut name = the_dir.clone();
         match gethostname() {
@@ -808,71 +1288,152 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                 name.push(format!("routing-{}.key", hostname));
             },
         };
-        read_or_generate_keypair(name).unwrap()
+        try!(read_or_generate_keypair(name, passphrase))
     };
+    let mut config = config.clone();
+    if config.state_dir.is_none() {
+        config.state_dir = Some(the_dir.clone());
+    }
+    start_node(my_key, &config, UdpTransport(config.listen.clone()),
+               Arc::new(SystemClock), Box::new(OsRng))
+}
 
-    let send_period_ms = 1000*10;
-    let dht = DHT::new(&my_key, send_period_ms);
+/// Start relaying messages as `my_key`, sending and receiving packets
+/// via `transport`, telling the time by `clock`, and choosing routes
+/// with `rng`.
+pub fn start_node<T: Transport>(my_key: SecretKeyPair, config: &NodeConfig, transport: T,
+                                clock: Arc<Clock>, rng: Box<Rng>)
+                                -> Result<Node, Error> {
+    let send_period_ms = config.send_period_ms;
+    let known = match config.state_dir {
+        Some(ref d) => read_peers(d),
+        None => Vec::new(),
+    };
+    info!("Reloaded {} peers", known.len());
+    let my_public = my_key.public();
+    let my_key = Arc::new(my_key);
+    let dht = DHT::new(my_key.clone(), send_period_ms, &config.nodes(), &known, clock.clone(), rng);
 
-    let (send, get) = try!(udp::listen(send_period_ms));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (send, get, mut threads) = try!(transport.open(send_period_ms, stop.clone()));
 
     {
         // Here we set up the thread that sends out requests for
         // routing information.  This thread should wake up no more
-        // than once every 10 seconds (until I increase the
-        // communication frequency), and should ensure that we're
-        // always ready to send *something* out.
+        // than once every send period (10 seconds by default), and
+        // should ensure that we're always ready to send *something*
+        // out.
         let dht = dht.clone(); // a separate copy for sending
                                // maintenance requests.
-        std::thread::spawn(move|| {
+        let state_dir = config.state_dir.clone();
+        let stop = stop.clone();
+        threads.push(std::thread::spawn(move|| {
             let ms_period = send_period_ms;
             let buffer_ms = 100; // 100 ms seems enough...
-            let mut next_time = udp::now_ms()/ms_period*ms_period - buffer_ms;
+            let mut next_time = clock.now_ms()/ms_period*ms_period - buffer_ms;
+            let mut scheduling = ScheduleStats::default();
             loop {
+                if stop.load(Ordering::SeqCst) {
+                    // Send the earliest of what we had scheduled (still
+                    // paced by the transport, but with no cover
+                    // traffic in between) and save our peers before
+                    // closing down.
+                    let mut pending = dht.with_lock(|dht| { dht.drain_scheduled() });
+                    if pending.len() > MAX_FLUSHED {
+                        info!("Dropping {} scheduled packets", pending.len() - MAX_FLUSHED);
+                        pending.truncate(MAX_FLUSHED);
+                    }
+                    info!("Flushing {} scheduled packets", pending.len());
+                    for m in pending {
+                        if send.send(m).is_err() {
+                            break;
+                        }
+                    }
+                    if let Some(ref dir) = state_dir {
+                        let peers = dht.with_lock(|dht| { dht.known_peers() });
+                        if let Err(e) = write_peers(dir, &peers) {
+                            info!("Unable to save peers: {}", e);
+                        }
+                    }
+                    return;
+                }
                 let idx = (next_time/ms_period) as usize;
-                if !udp::sleep_until(next_time) {
+                if !clock.sleep_until(next_time) {
                     // We are behind, so try to catch up by sleeping extra
                     // long this time.
                     next_time += ms_period;
                 }
                 next_time += ms_period;
-                send.send(dht.name_lock("send", |dht| {dht.msg(idx)})).unwrap();
+                if let Some(m) = dht.name_lock("send", |dht| {dht.msg(idx)}) {
+                    if let Err(e) = send.send(m) {
+                        info!("Stopping maintenance requests: {}", Error::from(e));
+                        return;
+                    }
+                }
+                if idx % EXPIRE_PERIODS == 0 {
+                    let mut dht = dht.lock().unwrap();
+                    if dht.expire_stale() {
+                        info!("Expired unanswered: {:?}", dht.expired);
+                    }
+                    if dht.scheduling != scheduling {
+                        scheduling = dht.scheduling;
+                        info!("Transmission queue depth {}: {:?}", dht.queue_depth(), scheduling);
+                    }
+                }
+                if let Some(ref dir) = state_dir {
+                    if idx % SAVE_PEERS_PERIODS == 0 {
+                        let peers = dht.with_lock(|dht| { dht.known_peers() });
+                        if let Err(e) = write_peers(dir, &peers) {
+                            info!("Unable to save peers: {}", e);
+                        }
+                    }
+                }
             }
-        });
+        }));
     }
 
     let (sender1, receiver1): (Sender<EncryptedMessage>,
                                Receiver<EncryptedMessage>) = channel(); // for sending messages from this node
-    let (sender2, receiver2) = channel(); // for delivering messages to this node
+    let inbox = Arc::new(Inbox::new()); // for delivering messages to this node
+    let feed = InboxFeed(inbox.clone());
 
-    let (send_rendezvous_query, receive_rendezvous_query) = sync_channel(0); // asking for
-    let (send_rendezvous_location, receive_rendezvous_location) = sync_channel(0); // asking for
+    let (send_query, receive_query): (Sender<Query>, Receiver<Query>) = channel();
 
     {
-        // a separate copy for locating rendezvous nodes
+        // a separate copy for answering queries about the network.
+        // Each query brings its own channel for the answer, so that
+        // answers cannot go astray.
         let dht = dht.clone();
-        std::thread::spawn(move|| {
-            for recipient in receive_rendezvous_query.iter() {
-                let dht = dht.lock().unwrap();
-                let mut best = bingley().key;
-                let mut best_distance = key_distance(&best, &recipient);
-                for k in dht.addresses.keys() {
-                    let k_distance = key_distance(k, &recipient);
-                    if k_distance < best_distance {
-                        best_distance = k_distance;
-                        best = *k;
-                    }
+        let stop = stop.clone();
+        threads.push(std::thread::spawn(move|| {
+            for query in receive_query.iter() {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                // An error here means the asker is no longer waiting.
+                match query {
+                    Query::Rendezvous(recipient, answer) => {
+                        let best = dht.lock().unwrap().rendezvous_nodes(&recipient);
+                        answer.send(best).ok();
+                    },
+                    Query::Status(answer) => {
+                        let status = dht.lock().unwrap().status();
+                        answer.send(status).ok();
+                    },
                 }
-                send_rendezvous_location.send(best).unwrap();
             }
-        });
+        }));
     }
 
     {
         // a separate copy for sending out user messages.
         let dht = dht.clone();
-        std::thread::spawn(move|| {
+        let stop = stop.clone();
+        threads.push(std::thread::spawn(move|| {
             for encrypted_message in receiver1.iter() {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
                 dht.with_lock(|dht|{
                     if let Some((ip,sm)) = dht.send_ciphertext(encrypted_message.rendezvous,
                                                                encrypted_message.contents, 600) {
@@ -889,29 +1450,31 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                     }
                 });
             }
-        });
+        }));
     }
 
-    std::thread::spawn(move|| {
+    // This thread finishes once the transport closes `get`.
+    threads.push(std::thread::spawn(move|| {
         for packet in get.iter() {
-            match onionbox_open(&packet.data, &my_key.secret) {
+            match onionbox_open(&packet.data, my_key.secret()) {
                 Ok(mut oob) => {
                     let routing = RoutingInfo::from_bytes(&oob.routing());
                     if routing.is_for_me {
-                        match oob.payload(&my_key) {
+                        match oob.payload(my_key.keypair()) {
                             Err(e) => {
                                 info!("Unable to read message! {:?}", e);
                             },
                             Ok(payload) => {
                                 if routing.who_am_i {
                                     let mut you_are = [0; PAYLOAD_LENGTH];
-                                    let mut gift = dht.name_lock("gift", |dht|{dht.construct_gift()});
-                                    gift[0] = RoutingGift{ addr: packet.ip,
-                                                           key: oob.key() };
+                                    let sender = RoutingGift{ addr: packet.ip,
+                                                              key: oob.key() };
                                     // add the sender to our database of routers
-                                    dht.name_lock("accept", |dht|{dht.accept_single_gift(&gift[0])});
+                                    dht.name_lock("accept", |dht|{dht.accept_single_gift(&sender)});
+                                    let mut gift = dht.name_lock("gift", |dht|{dht.construct_gift()});
+                                    gift[0] = sender;
                                     Message::Response(gift).bytes(&mut you_are);
-                                    oob.respond(&my_key, &you_are);
+                                    oob.respond(my_key.keypair(), &you_are);
                                     dht.name_lock("schedule",
                                                   |dht|{dht.schedule_if_convenient(routing.eta,
                                                                                    &udp::RawEncryptedMessage{
@@ -920,13 +1483,16 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                                                                                    })});
                                 } else {
                                     match Message::from_bytes(&payload) {
-                                        Message::Greetings(gs) => {
+                                        Err(e) => {
+                                            info!("Dropping {}: {}", codename(&packet.data), e);
+                                        },
+                                        Ok(Message::Greetings(gs)) => {
                                             dht.with_lock(|dht|{dht.accept_gift(&gs)});
 
                                             let mut response = [0; PAYLOAD_LENGTH];
                                             let gift = dht.with_lock(|dht|{dht.construct_gift()});
                                             Message::Response(gift).bytes(&mut response);
-                                            oob.respond(&my_key, &response);
+                                            oob.respond(my_key.keypair(), &response);
                                             // info!("Relaying {} {} -> {} {}",
                                             //          codename(&packet.data), packet.ip,
                                             //          codename(&oob.packet()), routing.ip);
@@ -936,9 +1502,9 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                                                                                  data: oob.packet(),
                                                                              })});
                                         },
-                                        Message::PickUp { destination, message } => {
-                                            // info!("   ═══ Pickup request!!! ═══ {}", my_key.public);
-                                            if let Ok((pk, _, _)) = double_unbox(&message, &my_key.secret) {
+                                        Ok(Message::PickUp { destination, message }) => {
+                                            // info!("   ═══ Pickup request!!! ═══ {}", my_key.public());
+                                            if let Ok((pk, _, _)) = double_unbox(&message, my_key.secret()) {
                                                 if pk != destination {
                                                     info!("Invalid pickup request: {}",
                                                           codename(&packet.data));
@@ -952,58 +1518,60 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                                             // info!("   ═══ Pickup request: {} for {} ═══",
                                             //       codename(&packet.data),
                                             //       codename(&destination.0));
+                                            // Each pickup request carries back at
+                                            // most one message, so a user with many
+                                            // waiting sends as many requests.
                                             let mut dht = dht.lock().unwrap();
-                                            let ready_to_pickup = dht.to_pickup.contains_key(&destination);
-                                            if ready_to_pickup {
-                                                let mut buffer = [0;544];
-                                                dht.to_pickup[&destination].bytes(&mut buffer);
-                                                oob.respond(&my_key, &buffer);
-                                                info!("Forwarding {} {} -> {} {}",
-                                                      codename(&destination.0), codename(&buffer),
-                                                      codename(&oob.packet()), routing.ip);
-                                                dht.schedule(routing.eta,
-                                                             &udp::RawEncryptedMessage{
-                                                                 ip: routing.ip,
-                                                                 data: oob.packet(),
-                                                             });
-                                            } else {
-                                                // info!("Eventually I will deliver {} to {} {}",
-                                                //       codename(&destination.0),
-                                                //       codename(&oob.packet()), routing.ip);
-                                                dht.to_forward.insert(destination, oob);
+                                            match dht.take_message(&destination) {
+                                                Some(message) => {
+                                                    let mut buffer = [0;544];
+                                                    Message::ForwardPlease{destination: destination,
+                                                                           message: message}.bytes(&mut buffer);
+                                                    oob.respond(my_key.keypair(), &buffer);
+                                                    info!("Forwarding {} {} -> {} {}",
+                                                          codename(&destination.0), codename(&buffer),
+                                                          codename(&oob.packet()), routing.ip);
+                                                    dht.schedule(routing.eta,
+                                                                 &udp::RawEncryptedMessage{
+                                                                     ip: routing.ip,
+                                                                     data: oob.packet(),
+                                                                 });
+                                                },
+                                                None => {
+                                                    // info!("Eventually I will deliver {} to {} {}",
+                                                    //       codename(&destination.0),
+                                                    //       codename(&oob.packet()), routing.ip);
+                                                    dht.queue_pickup(destination, routing.eta, oob);
+                                                },
                                             }
-                                            dht.to_pickup.remove(&destination);
                                         },
-                                        Message::ForwardPlease { destination, message } => {
+                                        Ok(Message::ForwardPlease { destination, message }) => {
                                             // info!("Forward request: {}", codename(&packet.data));
                                             let mut dht = dht.lock().unwrap();
-                                            let ready_to_forward = dht.to_forward.contains_key(&destination);
-                                            if ready_to_forward {
-                                                let mut buffer = [0;544];
-                                                Message::ForwardPlease{destination: destination,
-                                                                       message: message}.bytes(&mut buffer);
-                                                let (routing, packet) = {
-                                                    let ref mut foob = dht.to_forward.get_mut(&destination).unwrap();
-                                                    foob.respond(&my_key, &buffer);
+                                            match dht.take_pickup(&destination) {
+                                                Some(mut foob) => {
+                                                    let mut buffer = [0;544];
+                                                    Message::ForwardPlease{destination: destination,
+                                                                           message: message}.bytes(&mut buffer);
+                                                    foob.respond(my_key.keypair(), &buffer);
                                                     let routing = RoutingInfo::from_bytes(&foob.routing());
                                                     // info!("Forwarding {} {} -> {} {}",
                                                     //          codename(&destination.0), codename(&buffer),
                                                     //          codename(&foob.packet()),
                                                     //          routing.ip);
-                                                    (routing, foob.packet())
-                                                };
-                                                dht.schedule(routing.eta,
-                                                             &udp::RawEncryptedMessage{
-                                                                 ip: routing.ip,
-                                                                 data: packet,
-                                                             });
-                                                dht.to_forward.remove(&destination);
-                                            } else {
-                                                // info!("Saving message for pick up by {}!", codename(&destination.0));
-                                                dht.to_pickup.insert(destination,
-                                                                     Message::ForwardPlease{
-                                                                         destination: destination,
-                                                                         message: message });
+                                                    dht.schedule(routing.eta,
+                                                                 &udp::RawEncryptedMessage{
+                                                                     ip: routing.ip,
+                                                                     data: foob.packet(),
+                                                                 });
+                                                },
+                                                None => {
+                                                    // info!("Saving message for pick up by {}!", codename(&destination.0));
+                                                    if !dht.queue_message(destination, routing.eta, message) {
+                                                        info!("Too many messages waiting for {}",
+                                                              codename(&destination.0));
+                                                    }
+                                                },
                                             }
                                         },
                                         _ => {
@@ -1027,9 +1595,15 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                 _ => {
                     let maybe_msg = match dht.lock().unwrap().onionboxen.get(array_ref![packet.data,0,32]) {
                         Some(sm) =>
-                            match sm.ob.read_return(my_key, &packet.data) {
+                            match my_key.with_copy(|kp| { sm.ob.read_return(kp, &packet.data) }) {
                                 Ok(msg) => {
-                                    Some((sm.clone(),Message::from_bytes(&msg)))
+                                    match Message::from_bytes(&msg) {
+                                        Ok(m) => Some((sm.clone(), m)),
+                                        Err(e) => {
+                                            info!("Dropping response {}: {}", codename(&packet.data), e);
+                                            None
+                                        },
+                                    }
                                 },
                                 _ => {
                                     info!("Message illegible!");
@@ -1054,25 +1628,18 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                         Some((sm,Message::Response(rgs))) => {
                             dht.with_lock(|dht|{dht.accept_gift(&rgs)});
                             for i in 0 .. ROUTE_COUNT {
-                                if sm.who_relayed[i] != my_key.public {
+                                if sm.who_relayed[i] != my_key.public() {
                                     // println!("Increasing liveness for {}!", sm.who_relayed[i]);
-                                    dht.with_lock(|dht| {
-                                        dht.liveness.insert(sm.who_relayed[i],
-                                                            MAX_LIVENESS);
-                                        dht.newbies.remove(&sm.who_relayed[i]);
-                                    });
+                                    dht.with_lock(|dht| { dht.mark_live(&sm.who_relayed[i]) });
                                 }
                             }
-                            // if REPORT_WHOAMIS || sm.who_relayed[1] != my_key.public {
+                            // if REPORT_WHOAMIS || sm.who_relayed[1] != my_key.public() {
                             //     info!("Response received: {}", codename(&packet.data));
                             // }
                             dht.with_lock(|dht|{dht.print("routing worked")});
-                            if rgs[0].key == my_key.public {
+                            if rgs[0].key == my_key.public() {
                                 // println!("My address is {}", rgs[0].addr);
-                                dht.with_lock(|dht| {
-                                    dht.liveness.insert(my_key.public, MAX_LIVENESS);
-                                    dht.newbies.remove(&my_key.public);
-                                });
+                                dht.with_lock(|dht| { dht.mark_live(&my_key.public()) });
                             }
                         },
                         Some((_,Message::PickUp { destination, .. })) => {
@@ -1082,17 +1649,258 @@ pub fn start_static_node(the_dir: &std::path::PathBuf)
                         Some((_,Message::ForwardPlease { destination, message})) => {
                             // info!("Forward request: {} for {}",
                             //       codename(&packet.data), codename(&destination.0));
-                            sender2.send(UserMessage {
+                            feed.0.push(UserMessage {
                                 destination: destination,
                                 message: message,
-                            }).unwrap();
+                            });
                         },
                     }
                 },
             }
         }
-    });
-    Ok((send_rendezvous_query, receive_rendezvous_location, sender1, receiver2))
+    }));
+    let handle = NodeHandle {
+        stop: stop,
+        threads: threads,
+        wake_queries: send_query.clone(),
+        wake_sender: sender1.clone(),
+        my_public: my_public,
+    };
+    Ok(Node(Arc::new(NodeInner {
+        my_public: my_public,
+        queries: Mutex::new(send_query),
+        outgoing: Mutex::new(sender1),
+        incoming: inbox,
+        handle: Mutex::new(Some(handle)),
+    })))
+}
+
+/// The messages delivered to a node, waiting for its users.  Unlike a
+/// `Receiver`, it lets any number of threads wait at once, without
+/// any of them holding a lock while they wait.
+struct Inbox {
+    state: Mutex<InboxState>,
+    ready: Condvar,
+}
+
+struct InboxState {
+    messages: VecDeque<UserMessage>,
+    /// Whether the node has stopped delivering messages.
+    closed: bool,
+}
+
+impl Inbox {
+    fn new() -> Inbox {
+        Inbox {
+            state: Mutex::new(InboxState { messages: VecDeque::new(), closed: false }),
+            ready: Condvar::new(),
+        }
+    }
+    fn push(&self, m: UserMessage) {
+        self.state.lock().unwrap().messages.push_back(m);
+        self.ready.notify_one();
+    }
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+    fn try_pop(&self) -> Option<UserMessage> {
+        self.state.lock().unwrap().messages.pop_front()
+    }
+    /// Wait for the next message, failing once the inbox is closed
+    /// and empty.
+    fn pop(&self) -> Result<UserMessage, Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(m) = state.messages.pop_front() {
+                return Ok(m);
+            }
+            if state.closed {
+                return Err(Error::ChannelClosed);
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+}
+
+/// The delivering end of an `Inbox`, which closes it when dropped, as
+/// happens when the thread that owns it finishes.
+struct InboxFeed(Arc<Inbox>);
+
+impl Drop for InboxFeed {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// A question for a running node, with a channel for the answer.
+enum Query {
+    Rendezvous(crypto::PublicKey, SyncSender<Vec<crypto::PublicKey>>),
+    Status(SyncSender<NodeStatus>),
+}
+
+/// A summary of the state of a running node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeStatus {
+    /// Our own address, once some other node has told us it.
+    pub my_addr: Option<SocketAddr>,
+    /// The number of nodes in our routing table.
+    pub known_peers: usize,
+    /// The number of nodes that have recently relayed for us.
+    pub live_peers: usize,
+    /// The number of packets waiting to be sent.
+    pub queue_depth: usize,
+    /// The number of messages we are holding for others to pick up.
+    pub waiting_messages: usize,
+    /// The number of pickup requests we are holding.
+    pub waiting_pickups: usize,
+    /// What we have thrown away for want of a response or pickup.
+    pub expired: ExpiryStats,
+    /// What has happened to packets that did not fit in our schedule.
+    pub scheduling: ScheduleStats,
+}
+
+/// A running node.  It may be cloned and shared between threads, and
+/// shuts down once the last clone is dropped.
+#[derive(Clone)]
+pub struct Node(Arc<NodeInner>);
+
+struct NodeInner {
+    my_public: crypto::PublicKey,
+    queries: Mutex<Sender<Query>>,
+    outgoing: Mutex<Sender<EncryptedMessage>>,
+    incoming: Arc<Inbox>,
+    handle: Mutex<Option<NodeHandle>>,
+}
+
+impl Node {
+    /// The routing key of this node.
+    pub fn public_key(&self) -> crypto::PublicKey {
+        self.0.my_public
+    }
+    fn ask<T>(&self, query: Query, answer: Receiver<T>) -> Result<T, Error> {
+        try!(self.0.queries.lock().unwrap().send(query));
+        Ok(try!(answer.recv()))
+    }
+    /// The nodes at which messages for `k` are deposited, closest
+    /// first.
+    pub fn rendezvous(&self, k: &crypto::PublicKey) -> Result<Vec<crypto::PublicKey>, Error> {
+        let (tx, rx) = sync_channel(1);
+        self.ask(Query::Rendezvous(*k, tx), rx)
+    }
+    /// A summary of how the node is doing, which fails once it has
+    /// shut down.
+    pub fn status(&self) -> Result<NodeStatus, Error> {
+        let (tx, rx) = sync_channel(1);
+        self.ask(Query::Status(tx), rx)
+    }
+    fn send_payload(&self, rendezvous: crypto::PublicKey, msg: &Message) -> Result<(), Error> {
+        let mut p = [0; PAYLOAD_LENGTH];
+        msg.bytes(&mut p);
+        try!(self.0.outgoing.lock().unwrap().send(EncryptedMessage {
+            rendezvous: rendezvous,
+            contents: p,
+        }));
+        Ok(())
+    }
+    /// Deposit the `double_box`ed `ciphertext` for `destination` with
+    /// each of its rendezvous.  The recipient ignores any duplicates.
+    pub fn send_ciphertext(&self, destination: &crypto::PublicKey,
+                           ciphertext: &[u8; USER_MESSAGE_LENGTH]) -> Result<(), Error> {
+        for ren in try!(self.rendezvous(destination)) {
+            try!(self.send_payload(ren, &Message::ForwardPlease {
+                destination: *destination,
+                message: *ciphertext,
+            }));
+        }
+        Ok(())
+    }
+    /// Ask each of our rendezvous for a message addressed to `who`.
+    pub fn pickup(&self, who: &SecretKeyPair) -> Result<(), Error> {
+        for ren in try!(self.rendezvous(&who.public())) {
+            // The rendezvous checks that this came from `who`.
+            let msg = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+            let (_, c) = double_box(&msg, &ren, who.keypair());
+            try!(self.send_payload(ren, &Message::PickUp {
+                destination: who.public(),
+                message: c,
+            }));
+        }
+        Ok(())
+    }
+    /// The next message delivered to us, if there is one yet.
+    pub fn try_receive(&self) -> Option<UserMessage> {
+        self.0.incoming.try_pop()
+    }
+    /// Wait for the next message delivered to us.  This fails once the
+    /// node has shut down.
+    pub fn receive(&self) -> Result<UserMessage, Error> {
+        self.0.incoming.pop()
+    }
+    /// The messages delivered to us, as they arrive.
+    pub fn incoming(&self) -> Incoming {
+        Incoming(self.clone())
+    }
+    /// Stop the node, after sending whatever transmissions it had
+    /// scheduled and saving its peers, and wait for all its threads
+    /// to finish.  This takes up to `MAX_FLUSHED` + 1 send periods,
+    /// and affects every clone of this `Node`.
+    pub fn shutdown(&self) {
+        let handle = self.0.handle.lock().unwrap().take();
+        if let Some(mut handle) = handle {
+            handle.stop_threads();
+        }
+    }
+}
+
+/// An iterator over the messages delivered to a `Node`, which ends
+/// when the node shuts down.
+pub struct Incoming(Node);
+
+impl Iterator for Incoming {
+    type Item = UserMessage;
+    fn next(&mut self) -> Option<UserMessage> {
+        self.0.receive().ok()
+    }
+}
+
+/// The threads of a running node.  Dropping it shuts the node down.
+struct NodeHandle {
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    // These let us wake the threads that wait on channels belonging
+    // to the users of the node.
+    wake_queries: Sender<Query>,
+    wake_sender: Sender<EncryptedMessage>,
+    my_public: crypto::PublicKey,
+}
+
+impl NodeHandle {
+    fn stop_threads(&mut self) {
+        if self.threads.is_empty() {
+            return;
+        }
+        self.stop.store(true, Ordering::SeqCst);
+        // Each of these fails only if its thread has already gone.
+        let (tx, _) = sync_channel(1);
+        self.wake_queries.send(Query::Status(tx)).ok();
+        self.wake_sender.send(EncryptedMessage {
+            rendezvous: self.my_public,
+            contents: [0; PAYLOAD_LENGTH],
+        }).ok();
+        while let Some(t) = self.threads.pop() {
+            if t.join().is_err() {
+                info!("A node thread panicked");
+            }
+        }
+        info!("Node stopped");
+    }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        self.stop_threads();
+    }
 }
 
 pub struct UserMessage {
@@ -1118,6 +1926,17 @@ const NEW_LENGTH: usize = USER_MESSAGE_LENGTH - 96;
 
 pub fn double_box(p: &[u8; NEW_LENGTH], pk: &crypto::PublicKey, my: &crypto::KeyPair)
                   -> (message::Id, [u8; USER_MESSAGE_LENGTH]) {
+    double_box_with(p, pk, pk, &my.public, &my.secret)
+}
+
+/// Like `double_box`, but the inner box is between `inner_sk` and
+/// `inner_pk`, which may be session keys rather than our long-term
+/// keys.  The message still says it is from `me`, and the outer box
+/// is still addressed to `pk`.
+pub fn double_box_with(p: &[u8; NEW_LENGTH], pk: &crypto::PublicKey,
+                       inner_pk: &crypto::PublicKey, me: &crypto::PublicKey,
+                       inner_sk: &crypto::SecretKey)
+                       -> (message::Id, [u8; USER_MESSAGE_LENGTH]) {
     let mut plain = [0u8; USER_MESSAGE_LENGTH];
     *array_mut_ref![plain, USER_MESSAGE_LENGTH - NEW_LENGTH, NEW_LENGTH] = *p;
     let k = crypto::box_keypair();
@@ -1125,8 +1944,8 @@ pub fn double_box(p: &[u8; NEW_LENGTH], pk: &crypto::PublicKey, my: &crypto::Key
     let mut first_box = [0u8; USER_MESSAGE_LENGTH];
     crypto::box_up(array_mut_ref![first_box, 64, NEW_LENGTH+32],
                    array_ref![plain, 64, NEW_LENGTH+32],
-                   &n, pk, &my.secret);
-    *array_mut_ref![first_box,48,32] = my.public.0;
+                   &n, inner_pk, inner_sk);
+    *array_mut_ref![first_box,48,32] = me.0;
     // The msg_id is the first 32 bytes of ciphertext of the first
     // box, which includes the 16 authentication bytes.
     let msg_id = message::Id(*array_ref![first_box,48+32,32]);
@@ -1139,7 +1958,38 @@ pub fn double_box(p: &[u8; NEW_LENGTH], pk: &crypto::PublicKey, my: &crypto::Key
 }
 
 pub fn double_unbox(c: &[u8; USER_MESSAGE_LENGTH], sk: &crypto::SecretKey)
-                    -> Result<(crypto::PublicKey, message::Id, [u8; NEW_LENGTH]), crypto::NaClError> {
+                    -> Result<(crypto::PublicKey, message::Id, [u8; NEW_LENGTH]), Error> {
+    let inner = try!(double_unbox_outer(c, sk));
+    let out = try!(inner.open(&inner.from, sk));
+    Ok((inner.from, inner.msg_id, out))
+}
+
+/// A message whose outer box we have opened, so that we know who it
+/// claims to be from, but whose inner box may need a session key.
+pub struct InnerBox {
+    pub from: crypto::PublicKey,
+    pub msg_id: message::Id,
+    nonce: crypto::Nonce,
+    first_box: [u8; USER_MESSAGE_LENGTH],
+}
+
+impl InnerBox {
+    /// Open the inner box, which was made with the secret key
+    /// corresponding to `pk`, and the public key corresponding to `sk`.
+    pub fn open(&self, pk: &crypto::PublicKey, sk: &crypto::SecretKey)
+                -> Result<[u8; NEW_LENGTH], Error> {
+        let mut plain = [0u8; USER_MESSAGE_LENGTH];
+        try!(crypto::box_open(array_mut_ref![plain, 64, NEW_LENGTH+32],
+                              array_ref![self.first_box, 64, NEW_LENGTH+32],
+                              &self.nonce, pk, sk));
+        let (_, out) = array_refs!(&plain, 64+32, 415);
+        Ok(*out)
+    }
+}
+
+/// Open the outer box of a `double_box`ed message addressed to `sk`.
+pub fn double_unbox_outer(c: &[u8; USER_MESSAGE_LENGTH], sk: &crypto::SecretKey)
+                          -> Result<InnerBox, Error> {
     let mut second_box = *c;
     let ephemera = crypto::PublicKey(*array_ref![second_box, 0, 32]);
     *array_mut_ref![second_box, 0, 32] = [0;32];
@@ -1147,17 +1997,290 @@ pub fn double_unbox(c: &[u8; USER_MESSAGE_LENGTH], sk: &crypto::SecretKey)
     try!(crypto::box_open(array_mut_ref![first_box, 16, NEW_LENGTH+80],
                           array_ref![second_box, 16, NEW_LENGTH+80],
                           &crypto::Nonce([0;24]), &ephemera, sk));
-    // The msg_id is the first 32 bytes of ciphertext of the first
-    // box, which includes the 16 authentication bytes.
-    let msg_id = message::Id(*array_ref![first_box,48+32,32]);
-    let n = crypto::Nonce(*array_ref![ephemera.0, 0, 24]);
-    let pk = crypto::PublicKey(*array_ref![first_box,48,32]);
-    let mut plain = [0u8; USER_MESSAGE_LENGTH];
-    try!(crypto::box_open(array_mut_ref![plain, 64, NEW_LENGTH+32],
-                          array_ref![first_box, 64, NEW_LENGTH+32],
-                          &n, &pk, sk));
-    let (_, out) = array_refs!(&plain, 64+32, 415);
-    Ok((pk, msg_id, *out))
+    Ok(InnerBox {
+        from: crypto::PublicKey(*array_ref![first_box,48,32]),
+        // The msg_id is the first 32 bytes of ciphertext of the first
+        // box, which includes the 16 authentication bytes.
+        msg_id: message::Id(*array_ref![first_box,48+32,32]),
+        nonce: crypto::Nonce(*array_ref![ephemera.0, 0, 24]),
+        first_box: first_box,
+    })
+}
+
+/// `n` made-up nodes, at addresses 10.0.0.1 and up.
+#[cfg(test)]
+fn fake_gifts(n: usize) -> Vec<RoutingGift> {
+    (0 .. n).map(|i| {
+        RoutingGift {
+            addr: SocketAddr::from_str(&format!("10.0.0.{}:54321", i+1)).unwrap(),
+            key: crypto::box_keypair().public,
+        }
+    }).collect()
+}
+
+#[test]
+fn test_schedule_with_fake_clock() {
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000*1000*1000);
+    let period = 10*1000;
+    let me = Arc::new(SecretKeyPair::generate());
+    let others = fake_gifts(5);
+    let dht = DHT::new(me.clone(), period, &others, &[], Arc::new(clock.clone()), Box::new(OsRng));
+    let start = clock.now_ms();
+    let eta = (start/1000) as u32 + 60;
+    let target = udp::RawEncryptedMessage { ip: others[0].addr, data: [7; udp::PACKET_LENGTH] };
+    dht.with_lock(|dht| { dht.schedule(eta, &target) });
+    // An hour of sending one packet every period, which takes no
+    // time at all on a fake clock.
+    let mut sent_at = Vec::new();
+    for _ in 0 .. TIMER_WINDOW {
+        clock.advance(period);
+        let idx = (clock.now_ms()/period) as usize;
+        let m = dht.with_lock(|dht| { dht.msg(idx) }).unwrap(); // cover traffic if nothing else
+        if m.data.iter().all(|&b| { b == 7 }) {
+            assert_eq!(m.ip, others[0].addr);
+            sent_at.push(clock.now_ms());
+        }
+    }
+    assert_eq!(sent_at.len(), 1);
+    assert!(sent_at[0] > start);
+    assert!(sent_at[0] <= eta as u64 * 1000);
+}
+
+#[test]
+fn test_seeded_routes() {
+    use rng::SeededRng;
+    let me = Arc::new(SecretKeyPair::generate());
+    let others = fake_gifts(8);
+    let clock = Arc::new(clock::FakeClock::new(1000*1000*1000));
+    let a = DHT::new(me.clone(), 1000, &others, &[], clock.clone(), Box::new(SeededRng::new(7)));
+    let b = DHT::new(me.clone(), 1000, &others, &[], clock.clone(), Box::new(SeededRng::new(7)));
+    for _ in 0 .. 20 {
+        let ra = a.with_lock(|dht| { dht.pick_route() });
+        let rb = b.with_lock(|dht| { dht.pick_route() });
+        assert_eq!(ra, rb);
+    }
+}
+
+#[test]
+fn test_rendezvous_nodes() {
+    let me = Arc::new(SecretKeyPair::generate());
+    let others = fake_gifts(50);
+    let clock = Arc::new(clock::FakeClock::new(1000*1000*1000));
+    let a = DHT::new(me.clone(), 1000, &others, &[], clock.clone(), Box::new(OsRng));
+    for _ in 0 .. 10 {
+        let target = crypto::box_keypair().public;
+        let mut all: Vec<_> = others.iter().map(|g| { g.key }).collect();
+        all.push(me.public());
+        all.sort_by(|x, y| { routing::distance(x, &target).cmp(&routing::distance(y, &target)) });
+        let ren = a.with_lock(|dht| { dht.rendezvous_nodes(&target) });
+        assert_eq!(&ren[..], &all[0 .. NUM_RENDEZVOUS]);
+    }
+    // Anyone looking for us will find that we are our own rendezvous.
+    assert_eq!(a.with_lock(|dht| { dht.rendezvous_nodes(&me.public()) })[0], me.public());
+}
+
+#[test]
+fn test_inbox() {
+    let inbox = Arc::new(Inbox::new());
+    let feed = InboxFeed(inbox.clone());
+    let waiter = {
+        let inbox = inbox.clone();
+        std::thread::spawn(move|| { inbox.pop().map(|m| { m.message[0] }) })
+    };
+    std::thread::sleep_ms(10);
+    // Someone waiting for a message does not keep others from looking.
+    assert!(inbox.try_pop().is_none());
+    feed.0.push(UserMessage { destination: crypto::box_keypair().public,
+                              message: [7; USER_MESSAGE_LENGTH] });
+    assert_eq!(waiter.join().unwrap().ok(), Some(7));
+    drop(feed);
+    assert!(inbox.pop().is_err());
+}
+
+#[test]
+fn test_full_bucket() {
+    let me = Arc::new(SecretKeyPair::generate());
+    let clock = Arc::new(clock::FakeClock::new(1000*1000*1000));
+    let a = DHT::new(me.clone(), 1000, &[], &[], clock.clone(), Box::new(OsRng));
+    // These all go in the bucket farthest from us.
+    let mut far = fake_gifts(routing::K + 5);
+    for g in far.iter_mut() {
+        g.key.0[0] = (g.key.0[0] & 0x7f) | (!me.public().0[0] & 0x80);
+    }
+    a.with_lock(|dht| {
+        for g in far[0 .. routing::K].iter() {
+            dht.accept_single_gift(g);
+        }
+        dht.liveness.insert(far[0].key, MAX_LIVENESS);
+        for g in far[routing::K ..].iter() {
+            dht.accept_single_gift(g);
+        }
+        // The live node is kept, and evicted nodes leave no trace.
+        assert_eq!(dht.addresses.len(), routing::K);
+        assert!(dht.addresses.contains_key(&far[0].key));
+        assert!(!dht.addresses.contains_key(&far[1].key));
+        assert_eq!(dht.pubkeys.len(), routing::K);
+        assert_eq!(dht.pubkeys.get(&far[1].addr), None);
+        assert!(!dht.newbies.contains(&far[1].key));
+        // A relay we evicted while its onion was in flight does not
+        // come back to life when the response arrives.
+        dht.mark_live(&far[1].key);
+        assert_eq!(dht.liveness.get(&far[1].key), None);
+        // Nor do we hand out a live node we have since evicted.
+        dht.liveness.insert(far[2].key, MAX_LIVENESS);
+        for _ in 0 .. 100 {
+            let g = dht.random_live_gift();
+            assert!(dht.addresses.contains_key(&g.key));
+        }
+        assert_eq!(dht.liveness.get(&far[2].key), None);
+    });
+}
+
+#[test]
+fn test_schedule_overflow() {
+    use clock::FakeClock;
+    let clock = FakeClock::new(1000*1000*1000);
+    let period = 10*1000;
+    let me = Arc::new(SecretKeyPair::generate());
+    let others = fake_gifts(5);
+    let dht = DHT::new(me.clone(), period, &others, &[], Arc::new(clock.clone()), Box::new(OsRng));
+    let eta = (clock.now_ms()/1000) as u32 + 60;
+    let extra = 10;
+    let num = TIMER_WINDOW + MAX_OVERFLOW + extra;
+    dht.with_lock(|dht| {
+        for i in 0 .. num {
+            let mut data = [0; udp::PACKET_LENGTH];
+            data[0] = 1;
+            data[1] = (i % 256) as u8;
+            data[2] = (i / 256) as u8;
+            dht.schedule(eta + i as u32, &udp::RawEncryptedMessage { ip: others[0].addr, data: data });
+        }
+        assert_eq!(dht.queue_depth(), TIMER_WINDOW + MAX_OVERFLOW);
+        assert_eq!(dht.scheduling.overflowed, (MAX_OVERFLOW + extra) as u64);
+        assert_eq!(dht.scheduling.dropped_full, extra as u64);
+        let whoami = udp::RawEncryptedMessage { ip: others[1].addr, data: [2; udp::PACKET_LENGTH] };
+        dht.schedule_if_convenient(eta, &whoami);
+        assert_eq!(dht.scheduling.dropped_inconvenient, 1);
+    });
+    // Everything we kept is sent, one packet per period, and nothing
+    // else is sent until it has all gone.
+    let mut sent = HashSet::new();
+    for _ in 0 .. TIMER_WINDOW + MAX_OVERFLOW {
+        clock.advance(period);
+        let idx = (clock.now_ms()/period) as usize;
+        let m = dht.with_lock(|dht| { dht.msg(idx) }).unwrap();
+        assert_eq!(m.data[0], 1);
+        sent.insert(m.data[1] as usize + 256*m.data[2] as usize);
+    }
+    assert_eq!(sent.len(), TIMER_WINDOW + MAX_OVERFLOW);
+    // The packets with the latest eta were the ones dropped.
+    assert!(sent.iter().all(|&i| { i < TIMER_WINDOW + MAX_OVERFLOW }));
+    assert_eq!(dht.with_lock(|dht| { dht.queue_depth() }), 0);
+}
+
+#[test]
+fn test_pickup_queue() {
+    let me = Arc::new(SecretKeyPair::generate());
+    let clock = clock::FakeClock::new(1000*1000*1000);
+    let a = DHT::new(me.clone(), 1000, &[], &[], Arc::new(clock.clone()), Box::new(OsRng));
+    let who = crypto::box_keypair().public;
+    let other = crypto::box_keypair().public;
+    let eta = (clock.now_ms()/1000) as u32;
+    a.with_lock(|dht| {
+        assert!(dht.queue_message(who, eta, [1; USER_MESSAGE_LENGTH]));
+        assert!(dht.queue_message(who, eta, [2; USER_MESSAGE_LENGTH]));
+        assert!(dht.queue_message(other, eta, [3; USER_MESSAGE_LENGTH]));
+        // A second message no longer overwrites the first.
+        assert_eq!(dht.take_message(&who).map(|m| { m[0] }), Some(1));
+        assert_eq!(dht.take_message(&who).map(|m| { m[0] }), Some(2));
+        assert!(dht.take_message(&who).is_none());
+        assert!(!dht.to_pickup.contains_key(&who));
+        for _ in 0 .. MAX_QUEUED_MESSAGES {
+            assert!(dht.queue_message(who, eta, [4; USER_MESSAGE_LENGTH]));
+        }
+        assert!(!dht.queue_message(who, eta, [5; USER_MESSAGE_LENGTH]));
+    });
+    clock.advance(MESSAGE_TTL_MS);
+    a.with_lock(|dht| {
+        assert!(dht.take_message(&who).is_none());
+        assert_eq!(dht.expired.messages, MAX_QUEUED_MESSAGES as u64);
+        assert!(dht.expire_stale());
+        assert_eq!(dht.expired.messages, MAX_QUEUED_MESSAGES as u64 + 1);
+        assert!(dht.to_pickup.is_empty());
+        let eta = (dht.clock.now_ms()/1000) as u32;
+        assert!(dht.queue_message(who, eta, [6; USER_MESSAGE_LENGTH]));
+        // An eta in the distant future does not keep a message forever.
+        assert!(dht.queue_message(other, std::u32::MAX, [7; USER_MESSAGE_LENGTH]));
+    });
+    clock.advance(TIMER_WINDOW as u64*1000 + MESSAGE_TTL_MS);
+    a.with_lock(|dht| {
+        assert!(dht.expire_stale());
+        assert!(dht.to_pickup.is_empty());
+    });
+}
+
+#[test]
+fn test_expire_onions() {
+    let me = Arc::new(SecretKeyPair::generate());
+    let others = fake_gifts(8);
+    let clock = clock::FakeClock::new(1000*1000*1000);
+    let a = DHT::new(me.clone(), 1000, &others, &[], Arc::new(clock.clone()), Box::new(OsRng));
+    a.with_lock(|dht| {
+        for i in 0 .. 10 {
+            assert!(dht.msg(i).is_some());
+        }
+        assert_eq!(dht.onionboxen.len(), 10);
+        assert!(!dht.expire_stale());
+    });
+    clock.advance(2*TIMER_WINDOW as u64*1000 + 10*1000);
+    a.with_lock(|dht| {
+        assert!(dht.expire_stale());
+        assert!(dht.onionboxen.is_empty());
+        assert_eq!(dht.expired.onions, 10);
+    });
+}
+
+#[test]
+fn test_saved_peers() {
+    let dir = format::test_dir();
+    assert_eq!(read_peers(&dir), Vec::new());
+    let me = Arc::new(SecretKeyPair::generate());
+    let others = fake_gifts(5);
+    let clock = Arc::new(clock::FakeClock::new(1000*1000*1000));
+    let a = DHT::new(me.clone(), 1000, &others, &[], clock.clone(), Box::new(OsRng));
+    a.with_lock(|dht| {
+        dht.liveness.insert(others[0].key, MAX_LIVENESS);
+        dht.liveness.insert(others[1].key, 1);
+        dht.addresses.insert(me.public(), SocketAddr::from_str("10.0.0.99:54321").unwrap(),
+                             &Liveness::new());
+    });
+    let peers = a.with_lock(|dht| { dht.known_peers() });
+    assert_eq!(peers.len(), others.len());
+    write_peers(&dir, &peers).unwrap();
+    let known = read_peers(&dir);
+    assert_eq!(known, peers);
+
+    let b = DHT::new(me.clone(), 1000, &[], &known, clock.clone(), Box::new(OsRng));
+    b.with_lock(|dht| {
+        assert_eq!(dht.addresses.len(), others.len());
+        assert!(!dht.addresses.contains_key(&me.public()));
+        assert_eq!(dht.liveness.get(&others[0].key), Some(&RESTORED_LIVENESS));
+        assert_eq!(dht.liveness.get(&others[1].key), Some(&1));
+        assert_eq!(dht.liveness.get(&others[2].key), None);
+        assert!(dht.newbies.contains(&others[2].key));
+    });
+}
+
+#[test]
+fn test_malformed_message() {
+    let mut p = [0; PAYLOAD_LENGTH];
+    assert!(Message::from_bytes(&p).is_err());
+    Message::PickUp { destination: crypto::box_keypair().public,
+                      message: [7; USER_MESSAGE_LENGTH] }.bytes(&mut p);
+    assert!(Message::from_bytes(&p).is_ok());
+    p[0] = b'z';
+    assert!(Message::from_bytes(&p).is_err());
 }
 
 #[test]
@@ -1177,3 +2300,22 @@ fn test_double_box() {
     assert_eq!(silly[5], stupid[5]);
     assert_eq!(silly[NEW_LENGTH-3], stupid[NEW_LENGTH-3]);
 }
+
+#[test]
+fn test_double_box_with() {
+    let mut stupid = [0; NEW_LENGTH];
+    stupid[5] = 3;
+    let k1 = crypto::box_keypair();
+    let k2 = crypto::box_keypair();
+    let s1 = crypto::box_keypair();
+    let s2 = crypto::box_keypair();
+    let (id, o) = double_box_with(&stupid, &k2.public, &s2.public, &k1.public, &s1.secret);
+    let inner = double_unbox_outer(&o, &k2.secret).unwrap();
+    assert_eq!(inner.from, k1.public);
+    assert_eq!(inner.msg_id, id);
+    // Only the session keys open the inner box, so the long-term
+    // keys alone cannot read it.
+    assert!(double_unbox(&o, &k2.secret).is_err());
+    assert!(inner.open(&k1.public, &s2.secret).is_err());
+    assert_eq!(inner.open(&s1.public, &s2.secret).unwrap()[5], 3);
+}
@@ -0,0 +1,79 @@
+use std;
+use onionsalt::crypto;
+
+/// The ways in which pmail can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// A key file was the wrong size, or otherwise not a key.
+    BadKeyFile,
+    /// A key file could not be decrypted with the passphrase given.
+    BadPassphrase,
+    /// A packet or message could not be decoded.
+    MalformedPacket,
+    /// Decryption or authentication failed.
+    Crypto,
+    /// Reading or writing a file (or socket) failed.
+    Storage(std::io::Error),
+    /// The thread at the other end of a channel has gone away.
+    ChannelClosed,
+    /// A message was too long to send.
+    TooLong,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match *self {
+            Error::BadKeyFile => f.write_str("bad key file"),
+            Error::BadPassphrase => f.write_str("wrong passphrase"),
+            Error::MalformedPacket => f.write_str("malformed packet"),
+            Error::Crypto => f.write_str("decryption failed"),
+            Error::Storage(ref e) => write!(f, "storage error: {}", e),
+            Error::ChannelClosed => f.write_str("channel closed"),
+            Error::TooLong => f.write_str("message too long"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::BadKeyFile => "bad key file",
+            Error::BadPassphrase => "wrong passphrase",
+            Error::MalformedPacket => "malformed packet",
+            Error::Crypto => "decryption failed",
+            Error::Storage(ref e) => e.description(),
+            Error::ChannelClosed => "channel closed",
+            Error::TooLong => "message too long",
+        }
+    }
+    fn cause(&self) -> Option<&std::error::Error> {
+        match *self {
+            Error::Storage(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Storage(e)
+    }
+}
+
+impl From<crypto::NaClError> for Error {
+    fn from(_: crypto::NaClError) -> Error {
+        Error::Crypto
+    }
+}
+
+impl From<std::sync::mpsc::RecvError> for Error {
+    fn from(_: std::sync::mpsc::RecvError) -> Error {
+        Error::ChannelClosed
+    }
+}
+
+impl<T> From<std::sync::mpsc::SendError<T>> for Error {
+    fn from(_: std::sync::mpsc::SendError<T>) -> Error {
+        Error::ChannelClosed
+    }
+}
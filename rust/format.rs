@@ -5,7 +5,7 @@ extern crate time;
 use std;
 use udp;
 
-#[derive(Debug,Copy,Clone,Hash,PartialEq,Eq,PartialOrd,Ord)]
+#[derive(Debug,Copy,Clone,Default,Hash,PartialEq,Eq,PartialOrd,Ord)]
 pub struct DateRfc3339(u32);
 
 pub fn epoch_to_rfc3339(t: u32) -> DateRfc3339 {
@@ -59,6 +59,36 @@ impl serde::ser::Serialize for DateRfc3339 {
 
 include!(concat!(env!("OUT_DIR"), "/format.rs"));
 
+/// Write `value` as json into the file `name`.
+pub fn write_json<P, T>(name: P, value: &T) -> Result<(), std::io::Error>
+    where P: AsRef<std::path::Path>, T: serde::Serialize {
+    let mut f = try!(std::fs::File::create(name));
+    match serde_json::to_writer(&mut f, value) {
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other,
+                                          format!("error writing json {}", e))),
+        _ => Ok(()),
+    }
+}
+
+/// Read a json value from the file `name`.
+pub fn read_json<P, T>(name: P) -> Result<T, std::io::Error>
+    where P: AsRef<std::path::Path>, T: serde::Deserialize {
+    let mut f = try!(std::fs::File::open(name));
+    match serde_json::from_reader(&mut f) {
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other,
+                                          format!("error reading json {}", e))),
+        Ok(v) => Ok(v),
+    }
+}
+
+/// A fresh directory for a test to keep its files in.
+#[cfg(test)]
+pub fn test_dir() -> std::path::PathBuf {
+    let dir = std::path::PathBuf::from(format!("/tmp/testing-{:x}", crypto::random_u64()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
 #[cfg(test)]
 mod test {
     use serde_json;
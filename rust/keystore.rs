@@ -0,0 +1,349 @@
+//! Key files, which hold a keypair encrypted with a key derived from
+//! a passphrase by scrypt.  A key file is laid out as
+//!
+//! ```text
+//! magic (8) | version (1) | log_n (1) | r (4) | p (4) | salt (16) | nonce (24) | secretbox of keypair (80)
+//! ```
+//!
+//! where `log_n`, `r` and `p` are the scrypt parameters.  Older key
+//! files, which hold just the 64 bytes of the keypair, or which are in
+//! version 1 (which derived its key by chaining XSalsa20, and had a
+//! four byte count of rounds in place of the scrypt parameters), are
+//! read too, and rewritten as soon as they are.
+
+use std;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use onionsalt::crypto;
+use rust_crypto::scrypt;
+
+use error::Error;
+#[cfg(test)]
+use format;
+use secret::{wipe, Passphrase, SecretKeyPair};
+
+/// The environment variable from which we read the passphrase,
+/// rather than asking for it.
+pub const PASSPHRASE_VAR: &'static str = "PMAIL_PASSPHRASE";
+
+const MAGIC: &'static [u8; 8] = b"pmailkey";
+const VERSION: u8 = 2;
+/// The scrypt parameters.  With these, deriving a key takes 16 MiB
+/// and a good fraction of a second, for us and for anyone guessing
+/// passphrases alike.  The tests use cheaper ones, so as not to take
+/// all day.
+#[cfg(not(test))]
+const LOG_N: u8 = 14;
+#[cfg(test)]
+const LOG_N: u8 = 8;
+const R: u32 = 8;
+const P: u32 = 1;
+
+const HEADER_LENGTH: usize = 8 + 1 + 1 + 4 + 4 + 16 + 24;
+const KEYPAIR_LENGTH: usize = 64;
+const FILE_LENGTH: usize = HEADER_LENGTH + 16 + KEYPAIR_LENGTH;
+
+const V1: u8 = 1;
+const V1_ROUNDS: u32 = 1 << 16;
+const V1_FILE_LENGTH: usize = 8 + 1 + 4 + 16 + 24 + 16 + KEYPAIR_LENGTH;
+
+fn be_u32(b: &[u8; 4]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32
+}
+
+fn u32_be(x: u32) -> [u8; 4] {
+    [(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]
+}
+
+/// Derive a key from `passphrase` with scrypt.
+fn derive_key(passphrase: &str, salt: &[u8; 16], log_n: u8, r: u32, p: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &scrypt::ScryptParams::new(log_n, r, p),
+                   &mut key);
+    key
+}
+
+/// One block of XSalsa20 keystream for `key` and `nonce`, which we
+/// get from `secretbox`.
+fn stir(key: &[u8; 32], nonce: &[u8; 24]) -> Result<[u8; 32], Error> {
+    let zeros = [0; 64];
+    let mut out = [0; 64];
+    try!(crypto::secretbox(&mut out, &zeros, &crypto::Nonce(*nonce), key));
+    Ok(*array_ref![out, 32, 32])
+}
+
+/// Derive a key from `passphrase` as version 1 did, by chaining
+/// XSalsa20, first over the passphrase and then `V1_ROUNDS` more
+/// times.  This is far too cheap to guess against, so we only use it
+/// to read version 1 files, which we then rewrite.
+fn v1_derive_key(passphrase: &str, salt: &[u8; 16]) -> Result<[u8; 32], Error> {
+    let mut key = [0; 32];
+    *array_mut_ref![key, 0, 16] = *salt;
+    let mut bytes = passphrase.as_bytes().to_vec();
+    bytes.push(0x80);
+    for chunk in bytes.chunks(24) {
+        let mut nonce = [0; 24];
+        for (n, &b) in nonce.iter_mut().zip(chunk.iter()) {
+            *n = b;
+        }
+        key = try!(stir(&key, &nonce));
+    }
+    wipe(&mut bytes);
+    let mut nonce = [0; 24];
+    *array_mut_ref![nonce, 0, 16] = *salt;
+    for i in 0 .. V1_ROUNDS {
+        *array_mut_ref![nonce, 16, 4] = u32_be(i);
+        key = try!(stir(&key, &nonce));
+    }
+    Ok(key)
+}
+
+fn random_bytes(out: &mut [u8]) {
+    for chunk in out.chunks_mut(32) {
+        let r = crypto::random_32();
+        for (o, &b) in chunk.iter_mut().zip(r.iter()) {
+            *o = b;
+        }
+    }
+}
+
+/// Save `kp` in `name`, encrypted with `passphrase`.
+pub fn write_keypair(name: &Path, kp: &SecretKeyPair, passphrase: &str) -> Result<(), Error> {
+    let mut salt = [0; 16];
+    random_bytes(&mut salt);
+    let mut nonce = [0; 24];
+    random_bytes(&mut nonce);
+    let mut key = derive_key(passphrase, &salt, LOG_N, R, P);
+
+    let mut plain = [0; 32 + KEYPAIR_LENGTH];
+    *array_mut_ref![plain, 32, 32] = kp.public().0;
+    *array_mut_ref![plain, 64, 32] = kp.secret().0;
+    let mut sealed = [0; 32 + KEYPAIR_LENGTH];
+    let sealed_ok = crypto::secretbox(&mut sealed, &plain, &crypto::Nonce(nonce), &key);
+    wipe(&mut plain);
+    wipe(&mut key);
+    try!(sealed_ok);
+
+    let mut data = [0; FILE_LENGTH];
+    {
+        let (m, v, log_n, r, p, s, n, c) = mut_array_refs!(&mut data, 8, 1, 1, 4, 4, 16, 24,
+                                                           16 + KEYPAIR_LENGTH);
+        *m = *MAGIC;
+        v[0] = VERSION;
+        log_n[0] = LOG_N;
+        *r = u32_be(R);
+        *p = u32_be(P);
+        *s = salt;
+        *n = nonce;
+        *c = *array_ref![sealed, 16, 16 + KEYPAIR_LENGTH];
+    }
+    // We write to a new file and rename it over the old, so that we
+    // never leave a half-written key behind.
+    let tmp = name.with_extension("tmp");
+    {
+        let mut f = try!(std::fs::File::create(&tmp));
+        try!(f.write_all(&data));
+        try!(f.sync_all());
+    }
+    try!(std::fs::rename(&tmp, name));
+    Ok(())
+}
+
+/// Open the `sealed` keypair (without its leading zeros) with `key`,
+/// wiping the key.
+fn open_keypair(sealed: &[u8; 16 + KEYPAIR_LENGTH], nonce: &[u8; 24], mut key: [u8; 32])
+                -> Result<SecretKeyPair, Error> {
+    let mut padded = [0; 32 + KEYPAIR_LENGTH];
+    *array_mut_ref![padded, 16, 16 + KEYPAIR_LENGTH] = *sealed;
+    let mut plain = [0; 32 + KEYPAIR_LENGTH];
+    let opened = crypto::secretbox_open(&mut plain, &padded, &crypto::Nonce(*nonce), &key);
+    wipe(&mut key);
+    if opened.is_err() {
+        return Err(Error::BadPassphrase);
+    }
+    let kp = SecretKeyPair::new(&mut crypto::KeyPair {
+        public: crypto::PublicKey(*array_ref![plain, 32, 32]),
+        secret: crypto::SecretKey(*array_ref![plain, 64, 32]),
+    });
+    wipe(&mut plain);
+    Ok(kp)
+}
+
+/// Read the keypair in `name`, which is encrypted with `passphrase`.
+/// A key file in an older format is rewritten in place.
+pub fn read_keypair(name: &Path, passphrase: &str) -> Result<SecretKeyPair, Error> {
+    let mut f = try!(std::fs::File::open(name));
+    let mut data = Vec::new();
+    try!(f.read_to_end(&mut data));
+    if data.len() == KEYPAIR_LENGTH {
+        let kp = SecretKeyPair::new(&mut crypto::KeyPair {
+            public: crypto::PublicKey(*array_ref![data, 0, 32]),
+            secret: crypto::SecretKey(*array_ref![data, 32, 32]),
+        });
+        wipe(&mut data);
+        info!("Encrypting old key file {:?}", name);
+        try!(write_keypair(name, &kp, passphrase));
+        return Ok(kp);
+    }
+    if data.len() < 9 || &data[0 .. 8] != &MAGIC[..] {
+        return Err(Error::BadKeyFile);
+    }
+    if data[8] == V1 && data.len() == V1_FILE_LENGTH {
+        let (_, _, r, s, n, c) = array_refs!(array_ref![data, 0, V1_FILE_LENGTH],
+                                             8, 1, 4, 16, 24, 16 + KEYPAIR_LENGTH);
+        if be_u32(r) != V1_ROUNDS {
+            return Err(Error::BadKeyFile);
+        }
+        let kp = try!(open_keypair(c, n, try!(v1_derive_key(passphrase, s))));
+        info!("Rewriting version 1 key file {:?}", name);
+        try!(write_keypair(name, &kp, passphrase));
+        return Ok(kp);
+    }
+    if data.len() != FILE_LENGTH || data[8] != VERSION {
+        return Err(Error::BadKeyFile);
+    }
+    let (_, _, log_n, r, p, s, n, c) = array_refs!(array_ref![data, 0, FILE_LENGTH],
+                                                   8, 1, 1, 4, 4, 16, 24, 16 + KEYPAIR_LENGTH);
+    if log_n[0] != LOG_N || be_u32(r) != R || be_u32(p) != P {
+        // Otherwise a corrupt (or malicious) file could have us spend
+        // all our memory, or all but forever, deriving its key.
+        return Err(Error::BadKeyFile);
+    }
+    open_keypair(c, n, derive_key(passphrase, s, LOG_N, R, P))
+}
+
+/// Turn echoing of what is typed on the terminal on or off.  This
+/// does nothing if stdin is not a terminal.
+fn set_echo(on: bool) {
+    let status = std::process::Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::null())
+        .status();
+    if let Err(e) = status {
+        info!("Unable to run stty: {}", e);
+    }
+}
+
+/// The passphrase for our key files, from `PMAIL_PASSPHRASE` if it is
+/// set, or else asked for on the terminal without echoing it.  An
+/// empty passphrase would leave our keys as good as unencrypted, so
+/// we refuse it.
+pub fn passphrase() -> Result<Passphrase, Error> {
+    let p = match std::env::var(PASSPHRASE_VAR) {
+        Ok(p) => Passphrase::new(p),
+        Err(_) => {
+            print!("Passphrase for pmail keys: ");
+            try!(std::io::stdout().flush());
+            set_echo(false);
+            // Room enough that the line is never moved (leaving a copy
+            // behind) while we read it.
+            let mut line = Passphrase::new(String::with_capacity(1024));
+            let read = line.read_line(&std::io::stdin());
+            set_echo(true);
+            println!("");
+            try!(read);
+            line.trim_newline();
+            line
+        },
+    };
+    if p.is_empty() {
+        println!("Refusing to use an empty passphrase.");
+        return Err(Error::BadPassphrase);
+    }
+    Ok(p)
+}
+
+#[cfg(test)]
+fn test_name() -> std::path::PathBuf {
+    format::test_dir().join("test.key")
+}
+
+#[test]
+fn test_keystore() {
+    let name = test_name();
+    let kp = SecretKeyPair::generate();
+    write_keypair(&name, &kp, "correct horse").unwrap();
+    let mut data = Vec::new();
+    std::fs::File::open(&name).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data.len(), FILE_LENGTH);
+    assert!(!data.windows(32).any(|w| { w == &kp.secret().0[..] }));
+
+    let kp2 = read_keypair(&name, "correct horse").unwrap();
+    assert_eq!(kp2.public(), kp.public());
+    assert_eq!(kp2.secret().0, kp.secret().0);
+    match read_keypair(&name, "battery staple") {
+        Err(Error::BadPassphrase) => (),
+        x => panic!("read with wrong passphrase: {:?}", x),
+    }
+
+    // A file that asks for other scrypt parameters is refused
+    // outright.
+    let mut odd = data.clone();
+    odd[9] = 0xff;
+    std::fs::File::create(&name).unwrap().write_all(&odd).unwrap();
+    match read_keypair(&name, "correct horse") {
+        Err(Error::BadKeyFile) => (),
+        x => panic!("read with other parameters: {:?}", x),
+    }
+
+    // A future version is not mistaken for a bad passphrase.
+    data[8] = VERSION + 1;
+    std::fs::File::create(&name).unwrap().write_all(&data).unwrap();
+    match read_keypair(&name, "correct horse") {
+        Err(Error::BadKeyFile) => (),
+        x => panic!("read unknown version: {:?}", x),
+    }
+}
+
+#[test]
+fn test_keystore_migration() {
+    let name = test_name();
+    let kp = crypto::box_keypair();
+    let mut raw = [0; KEYPAIR_LENGTH];
+    *array_mut_ref![raw, 0, 32] = kp.public.0;
+    *array_mut_ref![raw, 32, 32] = kp.secret.0;
+    std::fs::File::create(&name).unwrap().write_all(&raw).unwrap();
+
+    let kp2 = read_keypair(&name, "pass").unwrap();
+    assert_eq!(kp2.public(), kp.public);
+    assert_eq!(std::fs::metadata(&name).unwrap().len(), FILE_LENGTH as u64);
+    let kp3 = read_keypair(&name, "pass").unwrap();
+    assert_eq!(kp3.secret().0, kp.secret.0);
+    assert!(read_keypair(&name, "wrong").is_err());
+}
+
+#[test]
+fn test_keystore_v1() {
+    let name = test_name();
+    let kp = crypto::box_keypair();
+    let salt = [3; 16];
+    let nonce = [5; 24];
+    let key = v1_derive_key("pass", &salt).unwrap();
+    let mut plain = [0; 32 + KEYPAIR_LENGTH];
+    *array_mut_ref![plain, 32, 32] = kp.public.0;
+    *array_mut_ref![plain, 64, 32] = kp.secret.0;
+    let mut sealed = [0; 32 + KEYPAIR_LENGTH];
+    crypto::secretbox(&mut sealed, &plain, &crypto::Nonce(nonce), &key).unwrap();
+    let mut data = Vec::new();
+    data.extend(MAGIC.iter().cloned());
+    data.push(V1);
+    data.extend(u32_be(V1_ROUNDS).iter().cloned());
+    data.extend(salt.iter().cloned());
+    data.extend(nonce.iter().cloned());
+    data.extend(sealed[16 ..].iter().cloned());
+    assert_eq!(data.len(), V1_FILE_LENGTH);
+    std::fs::File::create(&name).unwrap().write_all(&data).unwrap();
+
+    match read_keypair(&name, "wrong") {
+        Err(Error::BadPassphrase) => (),
+        x => panic!("read version 1 with wrong passphrase: {:?}", x),
+    }
+    assert_eq!(std::fs::metadata(&name).unwrap().len(), V1_FILE_LENGTH as u64);
+    let kp2 = read_keypair(&name, "pass").unwrap();
+    assert_eq!(kp2.secret().0, kp.secret.0);
+    // It is rewritten with scrypt.
+    assert_eq!(std::fs::metadata(&name).unwrap().len(), FILE_LENGTH as u64);
+    assert_eq!(read_keypair(&name, "pass").unwrap().public(), kp.public);
+}
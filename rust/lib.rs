@@ -7,6 +7,8 @@ extern crate arrayref;
 extern crate log;
 
 extern crate onionsalt;
+// Only for scrypt, since everything else comes from onionsalt.
+extern crate crypto as rust_crypto;
 extern crate serde;
 extern crate serde_json;
 extern crate tempfile;
@@ -18,5 +20,16 @@ pub mod str255;
 pub mod message;
 pub mod mailbox;
 pub mod format;
+pub mod outbox;
+pub mod error;
+pub mod transport;
+pub mod simnet;
+pub mod clock;
+pub mod rng;
+pub mod routing;
+pub mod keystore;
+pub mod secret;
+pub mod session;
 
 pub use udp::{PACKET_LENGTH};
+pub use error::{Error};
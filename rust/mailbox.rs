@@ -4,6 +4,7 @@ extern crate time;
 extern crate lazyfs;
 
 use format;
+use format::{write_json};
 use pmail;
 use message;
 use udp;
@@ -46,62 +47,213 @@ impl Mailbox {
         use pmail::Message::*;
         match *msg {
             Comment { thread, time: epochtime, message_start, message_length, contents } => {
-                for u in &[*to, *from] {
-                    // first, save the fact that this user commented in this thread
-                    let mut userdir = try!(self.user_dir(u));
-                    userdir.push("threads");
-                    let mut users: Vec<_> = self.threads_from_user(u).collect();
-                    // info!("We already have {:?}", &users);
-                    let pos = users.iter().position(|x| { *x == thread });
-                    if let Some(pos) = pos {
-                        users.remove(pos);
-                    }
-                    users.push(thread);
-                    let mut f = try!(std::fs::File::create(userdir));
-                    match serde_json::to_writer(&mut f, &users) {
-                        Err(e) => { return Err(std::io::Error::new(std::io::ErrorKind::Other,
-                                                                   format!("error writing json {}", e))); }
-                        _ => {}
-                    }
+                // first, save the fact that these users take part in this
+                // thread, including everyone in a group thread.
+                for u in self.thread_recipients(thread).iter().chain(&[*to, *from]) {
+                    try!(self.add_user_thread(u, thread));
                 }
-                // now save the latest time of the thread
-                let name = try!(self.comment_name(thread, epochtime, msg_id));
-                let mut time_name = try!(self.thread_dir(thread));
-                time_name.push("time");
-
-                if message_start > 0 || message_length as usize > contents.len() {
-                    println!("I do not yet handle broken-up messages.");
+                // A bogus message_length must not make us allocate
+                // without bound.
+                if message_start > message_length || message_length > pmail::MAX_COMMENT_LENGTH {
+                    info!("Ignoring malformed comment fragment {}", msg_id);
+                    return Ok(());
+                }
+                let whole = if message_start == 0 && message_length as usize <= contents.len() {
+                    Some((msg_id, contents[0..message_length as usize].to_vec()))
                 } else {
+                    let end = std::cmp::min(message_length - message_start,
+                                            pmail::COMMENT_LENGTH as u32) as usize;
+                    try!(self.save_fragment(thread, epochtime, from, msg_id,
+                                            message_length, message_start,
+                                            &contents[0..end]))
+                };
+                if let Some((msg_id, bytes)) = whole {
+                    // now save the latest time of the thread
+                    let name = try!(self.comment_name(thread, epochtime, msg_id));
+                    let mut time_name = try!(self.thread_dir(thread));
+                    time_name.push("time");
+
                     let formatted = format::Message {
                         thread: thread,
                         time: format::epoch_to_rfc3339(epochtime),
                         id: msg_id,
                         from: *from,
-                        contents: String::from_utf8_lossy(&contents[0..message_length as usize]).to_string(),
+                        contents: String::from_utf8_lossy(&bytes).to_string(),
                     };
-                    {
-                        let mut f = try!(std::fs::File::create(time_name));
-                        match serde_json::to_writer(&mut f, &format::DateRfc3339::now()) {
-                            Err(e) => { return Err(std::io::Error::new(std::io::ErrorKind::Other,
-                                                                       format!("error writing json {}", e))); }
-                            _ => {}
-                        }
-                    }
-
-                    let mut f = try!(std::fs::File::create(name));
-                    match serde_json::to_writer(&mut f, &formatted) {
-                        Err(e) => { return Err(std::io::Error::new(std::io::ErrorKind::Other,
-                                                                   format!("error writing json {}", e))); }
-                        _ => {}
-                    }
+                    try!(write_json(time_name, &format::DateRfc3339::now()));
+                    try!(write_json(name, &formatted));
                 }
             },
+            ThreadSubject { thread, ref subject } => {
+                let len = subject.iter().position(|&b| { b == 0 }).unwrap_or(subject.len());
+                let subject = String::from_utf8_lossy(&subject[0..len]).to_string();
+                let mut name = try!(self.thread_dir(thread));
+                name.push("subject");
+                try!(write_json(name, &subject));
+            },
+            ThreadRecipients { thread, num_recipients, ref recipients } => {
+                let n = std::cmp::min(num_recipients as usize, recipients.len());
+                let recipients = format::Recipients(recipients[0..n].to_vec());
+                for u in recipients.0.iter().chain(&[*to, *from]) {
+                    try!(self.add_user_thread(u, thread));
+                }
+                let mut name = try!(self.thread_dir(thread));
+                name.push("recipients");
+                try!(write_json(name, &recipients));
+            },
             _ => {
                 info!("How do I save this?");
             },
         }
         Ok(())
     }
+    /// Record that `user` takes part in `thread`, moving the thread to
+    /// the end of the user's list of threads.
+    fn add_user_thread(&self, user: &crypto::PublicKey, thread: pmail::Thread)
+                       -> Result<(), std::io::Error> {
+        let mut userdir = try!(self.user_dir(user));
+        userdir.push("threads");
+        let mut threads: Vec<_> = self.threads_from_user(user).collect();
+        // info!("We already have {:?}", &threads);
+        let pos = threads.iter().position(|x| { *x == thread });
+        if let Some(pos) = pos {
+            threads.remove(pos);
+        }
+        threads.push(thread);
+        write_json(userdir, &threads)
+    }
+    fn delivery_name(&self, thread: pmail::Thread, comment: message::Id)
+                     -> Result<std::path::PathBuf, std::io::Error> {
+        let mut name = try!(self.thread_dir(thread));
+        name.push(format!("delivered-{}", comment));
+        Ok(name)
+    }
+    /// Record that `to` received the comment stored as `comment` in
+    /// `thread` at time `when`.
+    pub fn mark_delivered(&mut self, thread: pmail::Thread, comment: message::Id,
+                          to: &crypto::PublicKey, when: format::DateRfc3339)
+                          -> Result<(), std::io::Error> {
+        let mut deliveries = self.deliveries(thread, comment);
+        deliveries.retain(|d| { d.to != *to });
+        deliveries.push(format::Delivery { to: *to, time: when });
+        let name = try!(self.delivery_name(thread, comment));
+        write_json(name, &deliveries)
+    }
+    /// Who has received the comment stored as `comment` in `thread`,
+    /// and when.
+    pub fn deliveries(&self, thread: pmail::Thread, comment: message::Id) -> Vec<format::Delivery> {
+        match self.delivery_name(thread, comment) {
+            Ok(name) => format::read_json(name).unwrap_or(Vec::new()),
+            _ => Vec::new(),
+        }
+    }
+    /// The subject of `thread`, if anyone has told us one.
+    pub fn thread_subject(&self, thread: pmail::Thread) -> Option<String> {
+        let mut name = match self.thread_dir(thread) {
+            Ok(d) => d,
+            _ => { return None; },
+        };
+        name.push("subject");
+        std::fs::File::open(&name).ok().and_then(|mut f| {
+            serde_json::from_reader(&mut f).ok()
+        })
+    }
+    /// Everyone who participates in `thread`, according to the latest
+    /// `ThreadRecipients` we have seen for it.
+    pub fn thread_recipients(&self, thread: pmail::Thread) -> Vec<crypto::PublicKey> {
+        let mut name = match self.thread_dir(thread) {
+            Ok(d) => d,
+            _ => { return Vec::new(); },
+        };
+        name.push("recipients");
+        let recipients: Option<format::Recipients> = std::fs::File::open(&name).ok().and_then(|mut f| {
+            serde_json::from_reader(&mut f).ok()
+        });
+        match recipients {
+            Some(r) => r.0,
+            None => Vec::new(),
+        }
+    }
+    /// Store one fragment of a long comment on disk.  Once every byte
+    /// of the comment has arrived, the partial fragments are removed
+    /// and the reassembled comment is returned, along with the
+    /// `message::Id` of its first fragment, which serves as the id of
+    /// the whole comment.  Because the fragments live on disk, they
+    /// may arrive in any order, more than once, or across restarts.
+    /// Fragments belong to the same comment when they share a thread
+    /// (whose directory they are kept in), sender, `time` and length;
+    /// senders never reuse a `time`.  Once a comment is complete we
+    /// leave a marker in place of its fragments, so that a copy of one
+    /// that arrives late is ignored, rather than starting the comment
+    /// anew and lingering forever.
+    fn save_fragment(&self, thread: pmail::Thread, epochtime: u32, from: &crypto::PublicKey,
+                     msg_id: message::Id, message_length: u32, message_start: u32,
+                     contents: &[u8]) -> Result<Option<(message::Id, Vec<u8>)>, std::io::Error> {
+        use std::io::{Read, Write};
+        let mut dir = try!(self.thread_dir(thread));
+        dir.push("partial");
+        dir.push(format!("{}-{}-{}", from, epochtime, message_length));
+        let done = dir.with_extension("done");
+        if std::fs::metadata(&done).is_ok() {
+            return Ok(None);
+        }
+        try!(std::fs::create_dir_all(&dir));
+        {
+            let mut name = dir.clone();
+            name.push(format!("{}", message_start));
+            let mut f = try!(std::fs::File::create(name));
+            try!(f.write_all(contents));
+        }
+        if message_start == 0 {
+            let mut name = dir.clone();
+            name.push("id");
+            try!(write_json(name, &msg_id));
+        }
+
+        let mut starts = Vec::new();
+        let mut first_id = None;
+        for entry in try!(std::fs::read_dir(&dir)) {
+            let entry = try!(entry);
+            let filename = entry.file_name().to_string_lossy().to_string();
+            if filename == "id" {
+                let mut f = try!(std::fs::File::open(entry.path()));
+                first_id = serde_json::from_reader(&mut f).ok();
+            } else if let Ok(start) = filename.parse::<u32>() {
+                starts.push(start);
+            }
+        }
+        starts.sort();
+        let mut covered = 0;
+        for &start in starts.iter() {
+            if start > covered {
+                break;
+            }
+            let end = std::cmp::min(message_length,
+                                    start.saturating_add(pmail::COMMENT_LENGTH as u32));
+            covered = std::cmp::max(covered, end);
+        }
+        let first_id = match first_id {
+            Some(id) if covered >= message_length => id,
+            _ => { return Ok(None); }
+        };
+
+        let mut whole = vec![0; message_length as usize];
+        for &start in starts.iter() {
+            let mut name = dir.clone();
+            name.push(format!("{}", start));
+            let mut f = try!(std::fs::File::open(name));
+            let mut data = Vec::new();
+            try!(f.read_to_end(&mut data));
+            for i in 0 .. data.len() {
+                if start as usize + i < whole.len() {
+                    whole[start as usize + i] = data[i];
+                }
+            }
+        }
+        try!(std::fs::File::create(&done));
+        try!(std::fs::remove_dir_all(&dir));
+        Ok(Some((first_id, whole)))
+    }
     pub fn comment_name(&self, thread: pmail::Thread, epochtime: u32, id: message::Id)
                         -> Result<std::path::PathBuf, std::io::Error> {
         let mut dir = try!(self.thread_dir(thread));
@@ -291,7 +443,7 @@ fn test_threads() {
 
 #[test]
 fn test_mailbox() {
-    let name = format!("/tmp/testing-{:x}", crypto::random_u64());
+    let name = format::test_dir().display().to_string();
     println!("mailbox in {}", name);
     let mut mb = Mailbox::in_directory(&name).unwrap();
     let m1 = format::Message {
@@ -318,7 +470,7 @@ fn test_mailbox() {
     for m in &[&m1,&m2,&m3] {
         let len = m.contents.as_bytes().len();
         let cc = m.contents.as_bytes();
-        let mut c = [0;394];
+        let mut c = [0; pmail::COMMENT_LENGTH];
         for i in 0 .. len {
             c[i] = cc[i];
         }
@@ -330,7 +482,7 @@ fn test_mailbox() {
             contents: c,
         };
         println!("saving thread {} message '{}'", m.thread, m.contents);
-        mb.save(m.id, &m.from, &pm).unwrap();
+        mb.save(m.id, &m.from, &crypto::box_keypair().public, &pm).unwrap();
     }
     let mut got_t1 = false;
     let mut got_t2 = false;
@@ -363,3 +515,127 @@ fn test_mailbox() {
     assert!(got_m2);
     assert!(got_m3);
 }
+
+#[test]
+fn test_long_comment() {
+    let name = format::test_dir().display().to_string();
+    println!("mailbox in {}", name);
+    let from = crypto::box_keypair().public;
+    let to = crypto::box_keypair().public;
+    let thread = pmail::Thread::random();
+    let body: String = std::iter::repeat("long comment ").take(100).collect();
+    let frags: Vec<_> = pmail::comment_fragments(thread, 137, &body).unwrap().into_iter()
+        .map(|m| { (message::Id::random(), m) }).collect();
+    assert_eq!(frags.len(), 4);
+    {
+        let mut mb = Mailbox::in_directory(&name).unwrap();
+        for &i in &[2, 0, 2] {
+            mb.save(frags[i].0, &from, &to, &frags[i].1).unwrap();
+        }
+        assert_eq!(mb.comments_in_thread(thread).count(), 0);
+    }
+    // A fresh Mailbox, as after a restart, still has the fragments.
+    let mut mb = Mailbox::in_directory(&name).unwrap();
+    for &i in &[3, 0, 1] {
+        mb.save(frags[i].0, &from, &to, &frags[i].1).unwrap();
+    }
+    let cs: Vec<_> = mb.comments_in_thread(thread).collect();
+    assert_eq!(cs.len(), 1);
+    assert_eq!(cs[0].id, frags[0].0);
+    assert_eq!(cs[0].from, from);
+    assert_eq!(cs[0].contents, body);
+
+    // Late copies of its fragments leave nothing behind.
+    for &i in &[1, 2] {
+        mb.save(message::Id::random(), &from, &to, &frags[i].1).unwrap();
+    }
+    assert_eq!(mb.comments_in_thread(thread).count(), 1);
+    let mut partial = mb.thread_dir(thread).unwrap();
+    partial.push("partial");
+    for entry in std::fs::read_dir(&partial).unwrap() {
+        let path = entry.unwrap().path();
+        assert!(!std::fs::metadata(&path).unwrap().is_dir(), "{:?} lingers", path);
+    }
+}
+
+#[test]
+fn test_interleaved_comments() {
+    let name = format::test_dir().display().to_string();
+    println!("mailbox in {}", name);
+    let mut mb = Mailbox::in_directory(&name).unwrap();
+    let from = crypto::box_keypair().public;
+    let to = crypto::box_keypair().public;
+    let thread = pmail::Thread::random();
+    let a: String = std::iter::repeat("a").take(1000).collect();
+    let b: String = std::iter::repeat("b").take(1000).collect();
+    // Same sender, thread and length, as when one sends two comments
+    // in quick succession.
+    let fa = pmail::comment_fragments(thread, 137, &a).unwrap();
+    let fb = pmail::comment_fragments(thread, 138, &b).unwrap();
+    for (ma, mb_) in fa.iter().zip(fb.iter()) {
+        mb.save(message::Id::random(), &from, &to, mb_).unwrap();
+        mb.save(message::Id::random(), &from, &to, ma).unwrap();
+    }
+    let mut cs: Vec<_> = mb.comments_in_thread(thread).map(|c| { c.contents }).collect();
+    cs.sort();
+    assert_eq!(cs, vec![a, b]);
+}
+
+#[test]
+fn test_thread_info() {
+    let name = format::test_dir().display().to_string();
+    println!("mailbox in {}", name);
+    let mut mb = Mailbox::in_directory(&name).unwrap();
+    let me = crypto::box_keypair().public;
+    let you = crypto::box_keypair().public;
+    let them = crypto::box_keypair().public;
+    let thread = pmail::Thread::random();
+    assert_eq!(mb.thread_subject(thread), None);
+    assert_eq!(mb.thread_recipients(thread), Vec::new());
+    mb.save(message::Id::random(), &you, &me,
+            &pmail::Message::thread_subject(thread, "a nice subject")).unwrap();
+    mb.save(message::Id::random(), &you, &me,
+            &pmail::Message::thread_recipients(thread, &[me, you, them])).unwrap();
+    assert_eq!(mb.thread_subject(thread), Some("a nice subject".to_string()));
+    assert_eq!(mb.thread_recipients(thread), vec![me, you, them]);
+    assert!(mb.threads_from_user(&them).any(|t| { t == thread }));
+}
+
+#[test]
+fn test_group_comment() {
+    let name = format::test_dir().display().to_string();
+    println!("mailbox in {}", name);
+    let mut mb = Mailbox::in_directory(&name).unwrap();
+    let me = crypto::box_keypair().public;
+    let you = crypto::box_keypair().public;
+    let them = crypto::box_keypair().public;
+    let thread = pmail::Thread::random();
+    mb.save(message::Id::random(), &you, &me,
+            &pmail::Message::thread_recipients(thread, &[me, you, them])).unwrap();
+    for m in pmail::comment_fragments(thread, 137, "hello everyone").unwrap() {
+        mb.save(message::Id::random(), &you, &me, &m).unwrap();
+    }
+    for u in &[me, you, them] {
+        assert_eq!(mb.threads_from_user(u).last(), Some(thread));
+    }
+    assert_eq!(mb.comments_in_thread(thread).count(), 1);
+}
+
+#[test]
+fn test_deliveries() {
+    let name = format::test_dir().display().to_string();
+    println!("mailbox in {}", name);
+    let mut mb = Mailbox::in_directory(&name).unwrap();
+    let you = crypto::box_keypair().public;
+    let them = crypto::box_keypair().public;
+    let thread = pmail::Thread::random();
+    let comment = message::Id::random();
+    assert_eq!(mb.deliveries(thread, comment), Vec::new());
+    mb.mark_delivered(thread, comment, &you, format::epoch_to_rfc3339(10)).unwrap();
+    mb.mark_delivered(thread, comment, &them, format::epoch_to_rfc3339(20)).unwrap();
+    mb.mark_delivered(thread, comment, &you, format::epoch_to_rfc3339(30)).unwrap();
+    let ds = mb.deliveries(thread, comment);
+    assert_eq!(ds.len(), 2);
+    assert_eq!(ds[0], format::Delivery { to: them, time: format::epoch_to_rfc3339(20) });
+    assert_eq!(ds[1], format::Delivery { to: you, time: format::epoch_to_rfc3339(30) });
+}
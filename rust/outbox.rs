@@ -0,0 +1,392 @@
+//! The outbox holds the messages we have sent that need an
+//! acknowledgement.  Each one is kept on disk, along with its
+//! `double_box`ed ciphertext, so that we can keep resending it after
+//! a restart until it is acknowledged, or until its `RetryPolicy`
+//! tells us to give up.
+
+use std;
+use std::collections::HashMap;
+use onionsalt::crypto;
+#[cfg(test)]
+use serde_json;
+
+use dht::{USER_MESSAGE_LENGTH};
+use format;
+use format::{DeliveryState, OutgoingMessage, write_json, read_json};
+use message;
+use pmail;
+
+/// How often we resend unacknowledged messages, and when we give up
+/// on them.  All times are in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How long to wait after the first send before retrying.
+    pub initial_delay: u32,
+    /// The longest we ever wait between retries.  The delay doubles
+    /// after each attempt until it reaches this.
+    pub max_delay: u32,
+    /// How long after a message is queued we give up on it.
+    pub deadline: u32,
+    /// How long after its deadline we keep a message that is
+    /// acknowledged or given up on, so that its delivery state can
+    /// still be looked up, before forgetting it entirely.
+    pub retention: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            initial_delay: 60,
+            max_delay: 60*60,
+            deadline: 7*24*60*60,
+            retention: 7*24*60*60,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying a message that has been sent
+    /// `attempts` times.  The delay grows exponentially, and the
+    /// `jitter` (which should be random) picks a delay between half
+    /// and all of that, so that retries of many messages do not all
+    /// happen in lockstep.
+    pub fn delay(&self, attempts: u32, jitter: u32) -> u32 {
+        let mut d = std::cmp::max(self.initial_delay, 1);
+        for _ in 1 .. attempts {
+            d = d.saturating_mul(2);
+            if d >= self.max_delay {
+                break;
+            }
+        }
+        let d = std::cmp::min(d, std::cmp::max(self.max_delay, 1));
+        d - d/2 + jitter % (d/2 + 1)
+    }
+}
+
+pub struct Outbox {
+    dir: std::path::PathBuf,
+    messages: HashMap<message::Id, OutgoingMessage>,
+    policy: RetryPolicy,
+    /// Messages we have given up on, which nobody has yet been told
+    /// about.
+    failures: Vec<message::Id>,
+}
+
+impl Outbox {
+    /// Read the outbox stored in the "outbox" subdirectory of `the_dir`.
+    pub fn read(the_dir: &std::path::PathBuf) -> Result<Outbox, std::io::Error> {
+        let mut dir = the_dir.clone();
+        dir.push("outbox");
+        try!(std::fs::create_dir_all(&dir));
+        let mut messages = HashMap::new();
+        for entry in try!(std::fs::read_dir(&dir)) {
+            let entry = try!(entry);
+            match read_json::<_, OutgoingMessage>(entry.path()) {
+                Ok(m) => {
+                    messages.insert(m.id, m);
+                },
+                Err(e) => {
+                    info!("Could not read outbox entry {}: {}", entry.path().display(), e);
+                },
+            }
+        }
+        Ok(Outbox {
+            dir: dir,
+            messages: messages,
+            policy: RetryPolicy::default(),
+            failures: Vec::new(),
+        })
+    }
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+    fn write(&self, msg_id: &message::Id) -> Result<(), std::io::Error> {
+        if let Some(m) = self.messages.get(msg_id) {
+            let mut name = self.dir.clone();
+            name.push(format!("{}", msg_id));
+            try!(write_json(name, m));
+        }
+        Ok(())
+    }
+    /// Add a message that we are about to send to `to`.
+    pub fn insert(&mut self, msg_id: message::Id, to: &crypto::PublicKey,
+                  c: &[u8; USER_MESSAGE_LENGTH], now: format::DateRfc3339)
+                  -> Result<(), std::io::Error> {
+        self.messages.insert(msg_id, OutgoingMessage {
+            id: msg_id,
+            to: *to,
+            ciphertext: c.to_vec(),
+            state: DeliveryState::Queued,
+            queued: now,
+            attempts: 0,
+            last_sent: now,
+            next_attempt: now,
+            thread: None,
+            comment: None,
+        });
+        self.write(&msg_id)
+    }
+    fn set_state(&mut self, msg_id: &message::Id, state: DeliveryState)
+                 -> Result<(), std::io::Error> {
+        if let Some(m) = self.messages.get_mut(msg_id) {
+            m.state = state;
+            if state == DeliveryState::Acknowledged || state == DeliveryState::GaveUp {
+                // We will never send it again, so there is no need to
+                // keep the ciphertext around.
+                m.ciphertext = Vec::new();
+            }
+        }
+        self.write(msg_id)
+    }
+    /// Note that we have handed `msg_id` to the network at time
+    /// `now`, and schedule the next attempt.
+    pub fn mark_sent(&mut self, msg_id: &message::Id, now: format::DateRfc3339)
+                     -> Result<(), std::io::Error> {
+        let policy = self.policy;
+        match self.messages.get_mut(msg_id) {
+            Some(m) => {
+                if m.state != DeliveryState::Queued && m.state != DeliveryState::Sent {
+                    return Ok(());
+                }
+                m.state = DeliveryState::Sent;
+                m.attempts += 1;
+                m.last_sent = now;
+                let delay = policy.delay(m.attempts, crypto::random_u32());
+                m.next_attempt = format::epoch_to_rfc3339(
+                    format::rfc3339_to_epoch(now).saturating_add(delay));
+            },
+            None => { return Ok(()); },
+        }
+        self.write(msg_id)
+    }
+    /// The messages that are due to be resent at time `now`.  Any
+    /// message that has passed its deadline is given up on instead,
+    /// and reported by `take_failures`.  Finished messages that are
+    /// past their retention period are removed from the outbox.
+    pub fn due(&mut self, now: format::DateRfc3339)
+               -> Vec<(message::Id, crypto::PublicKey, [u8; USER_MESSAGE_LENGTH])> {
+        let now_secs = format::rfc3339_to_epoch(now);
+        let deadline = self.policy.deadline;
+        let retention = self.policy.retention;
+        let mut expired = Vec::new();
+        let mut old = Vec::new();
+        for m in self.messages.values() {
+            let deadline = format::rfc3339_to_epoch(m.queued).saturating_add(deadline);
+            if m.state == DeliveryState::Queued || m.state == DeliveryState::Sent {
+                if deadline <= now_secs {
+                    expired.push(m.id);
+                }
+            } else if deadline.saturating_add(retention) <= now_secs {
+                old.push(m.id);
+            }
+        }
+        for id in old {
+            self.remove(&id);
+        }
+        for id in expired {
+            info!("Giving up on message {} after {} attempts",
+                  id, self.messages[&id].attempts);
+            if let Err(e) = self.set_state(&id, DeliveryState::GaveUp) {
+                info!("Unable to update outbox: {}", e);
+            }
+            self.failures.push(id);
+        }
+        self.unacknowledged().into_iter().filter(|&(id, _, _)| {
+            self.messages[&id].next_attempt <= now
+        }).collect()
+    }
+    fn remove(&mut self, msg_id: &message::Id) {
+        self.messages.remove(msg_id);
+        let mut name = self.dir.clone();
+        name.push(format!("{}", msg_id));
+        if let Err(e) = std::fs::remove_file(&name) {
+            info!("Unable to remove outbox entry {}: {}", name.display(), e);
+        }
+    }
+    /// The messages we have given up on since the last call.
+    pub fn take_failures(&mut self) -> Vec<message::Id> {
+        std::mem::replace(&mut self.failures, Vec::new())
+    }
+    /// Note that `msg_id` belongs to the comment stored in our
+    /// `Mailbox` as `comment` in `thread`.
+    pub fn set_comment(&mut self, msg_id: &message::Id, thread: pmail::Thread,
+                       comment: message::Id) -> Result<(), std::io::Error> {
+        if let Some(m) = self.messages.get_mut(msg_id) {
+            m.thread = Some(thread);
+            m.comment = Some(comment);
+        }
+        self.write(msg_id)
+    }
+    /// Note that `from` has acknowledged `msg_id`.  Returns the
+    /// acknowledged message, or `None` if we were not waiting for
+    /// such an acknowledgement from `from`.
+    pub fn acknowledge(&mut self, from: &crypto::PublicKey, msg_id: &message::Id)
+                       -> Result<Option<OutgoingMessage>, std::io::Error> {
+        let waiting = match self.messages.get(msg_id) {
+            Some(m) => m.to == *from && (m.state == DeliveryState::Queued ||
+                                         m.state == DeliveryState::Sent),
+            None => false,
+        };
+        if !waiting {
+            return Ok(None);
+        }
+        try!(self.set_state(msg_id, DeliveryState::Acknowledged));
+        Ok(self.messages.get(msg_id).map(|m| { m.clone() }))
+    }
+    /// Whether every part of `comment` that we sent to `to` has been
+    /// acknowledged.
+    pub fn comment_acknowledged(&self, comment: &message::Id, to: &crypto::PublicKey) -> bool {
+        self.messages.values().all(|m| {
+            m.comment != Some(*comment) || m.to != *to || m.state == DeliveryState::Acknowledged
+        })
+    }
+    /// The delivery state of `msg_id`, if it is a message we sent.
+    pub fn state(&self, msg_id: &message::Id) -> Option<DeliveryState> {
+        self.messages.get(msg_id).map(|m| { m.state })
+    }
+    /// All the messages that still await an acknowledgement, along
+    /// with their recipients and ciphertext.
+    pub fn unacknowledged(&self) -> Vec<(message::Id, crypto::PublicKey, [u8; USER_MESSAGE_LENGTH])> {
+        let mut out = Vec::new();
+        for m in self.messages.values() {
+            if m.state != DeliveryState::Queued && m.state != DeliveryState::Sent {
+                continue;
+            }
+            if m.ciphertext.len() != USER_MESSAGE_LENGTH {
+                info!("Outbox entry {} has bad ciphertext", m.id);
+                continue;
+            }
+            out.push((m.id, m.to, *array_ref![m.ciphertext, 0, USER_MESSAGE_LENGTH]));
+        }
+        out
+    }
+}
+
+#[test]
+fn test_outbox() {
+    let name = format::test_dir();
+    let to = crypto::box_keypair().public;
+    let id1 = message::Id::random();
+    let id2 = message::Id::random();
+    let now = format::DateRfc3339::now();
+    {
+        let mut ob = Outbox::read(&name).unwrap();
+        ob.insert(id1, &to, &[1; USER_MESSAGE_LENGTH], now).unwrap();
+        ob.insert(id2, &to, &[2; USER_MESSAGE_LENGTH], now).unwrap();
+        ob.mark_sent(&id1, now).unwrap();
+        assert_eq!(ob.state(&id1), Some(DeliveryState::Sent));
+        assert_eq!(ob.state(&id2), Some(DeliveryState::Queued));
+        assert_eq!(ob.acknowledge(&crypto::box_keypair().public, &id2).unwrap(), None);
+        assert_eq!(ob.acknowledge(&to, &id2).unwrap().map(|m| { m.id }), Some(id2));
+        assert_eq!(ob.acknowledge(&to, &id2).unwrap(), None);
+    }
+    // Reading it again, as after a restart, gives back the same state.
+    let ob = Outbox::read(&name).unwrap();
+    assert_eq!(ob.state(&id1), Some(DeliveryState::Sent));
+    assert_eq!(ob.state(&id2), Some(DeliveryState::Acknowledged));
+    assert_eq!(ob.state(&message::Id::random()), None);
+    let un = ob.unacknowledged();
+    assert_eq!(un.len(), 1);
+    assert_eq!(un[0].0, id1);
+    assert_eq!(un[0].1, to);
+    assert_eq!(un[0].2[0], 1);
+}
+
+#[test]
+fn test_retry_delay() {
+    let p = RetryPolicy { initial_delay: 10, max_delay: 100, deadline: 1000, retention: 1000 };
+    for jitter in 0 .. 20 {
+        let d1 = p.delay(1, jitter);
+        assert!(d1 >= 5 && d1 <= 10);
+        let d2 = p.delay(2, jitter);
+        assert!(d2 >= 10 && d2 <= 20);
+        let d30 = p.delay(30, jitter);
+        assert!(d30 >= 50 && d30 <= 100);
+    }
+}
+
+#[test]
+fn test_retry_schedule() {
+    let name = format::test_dir();
+    let to = crypto::box_keypair().public;
+    let id = message::Id::random();
+    let at = |t: u32| { format::epoch_to_rfc3339(1000000 + t) };
+    let mut ob = Outbox::read(&name).unwrap();
+    ob.set_policy(RetryPolicy { initial_delay: 10, max_delay: 100, deadline: 1000, retention: 500 });
+    ob.insert(id, &to, &[1; USER_MESSAGE_LENGTH], at(0)).unwrap();
+    assert_eq!(ob.due(at(0)).len(), 1);
+    ob.mark_sent(&id, at(0)).unwrap();
+    assert_eq!(ob.due(at(4)).len(), 0);
+    assert_eq!(ob.due(at(10)).len(), 1);
+    let mut t = 10;
+    while t < 1000 {
+        if ob.due(at(t)).len() == 1 {
+            ob.mark_sent(&id, at(t)).unwrap();
+        }
+        t += 1;
+    }
+    // The retries back off exponentially, so there are only a few.
+    let attempts = ob.messages[&id].attempts;
+    assert!(attempts > 5 && attempts < 40);
+    assert_eq!(ob.take_failures(), Vec::new());
+    assert_eq!(ob.due(at(1000)).len(), 0);
+    assert_eq!(ob.state(&id), Some(DeliveryState::GaveUp));
+    assert_eq!(ob.take_failures(), vec![id]);
+    assert_eq!(ob.take_failures(), Vec::new());
+    // Once its retention period is over, we forget it entirely, even
+    // after a restart.
+    assert_eq!(ob.due(at(1499)).len(), 0);
+    assert_eq!(ob.state(&id), Some(DeliveryState::GaveUp));
+    assert_eq!(ob.due(at(1500)).len(), 0);
+    assert_eq!(ob.state(&id), None);
+    assert_eq!(Outbox::read(&name).unwrap().state(&id), None);
+}
+
+#[test]
+fn test_old_outbox_entry() {
+    // Entries written before we tracked retries still load.
+    let name = format::test_dir();
+    let to = crypto::box_keypair().public;
+    let id = message::Id::random();
+    let now = format::DateRfc3339::now();
+    {
+        let mut ob = Outbox::read(&name).unwrap();
+        ob.insert(id, &to, &[1; USER_MESSAGE_LENGTH], now).unwrap();
+    }
+    let mut entry = name.clone();
+    entry.push("outbox");
+    entry.push(format!("{}", id));
+    let mut json: serde_json::Value = read_json(&entry).unwrap();
+    for field in &["attempts", "last_sent", "next_attempt"] {
+        json.as_object_mut().unwrap().remove(*field);
+    }
+    write_json(&entry, &json).unwrap();
+    let mut ob = Outbox::read(&name).unwrap();
+    assert_eq!(ob.state(&id), Some(DeliveryState::Queued));
+    assert_eq!(ob.due(now).len(), 1);
+}
+
+#[test]
+fn test_comment_acknowledged() {
+    let name = format::test_dir();
+    let you = crypto::box_keypair().public;
+    let them = crypto::box_keypair().public;
+    let thread = pmail::Thread::random();
+    let comment = message::Id::random();
+    let now = format::DateRfc3339::now();
+    let mut ob = Outbox::read(&name).unwrap();
+    let ids: Vec<_> = (0..3).map(|_| { message::Id::random() }).collect();
+    ob.insert(ids[0], &you, &[0; USER_MESSAGE_LENGTH], now).unwrap();
+    ob.insert(ids[1], &you, &[0; USER_MESSAGE_LENGTH], now).unwrap();
+    ob.insert(ids[2], &them, &[0; USER_MESSAGE_LENGTH], now).unwrap();
+    for id in ids.iter() {
+        ob.set_comment(id, thread, comment).unwrap();
+    }
+    let m = ob.acknowledge(&you, &ids[0]).unwrap().unwrap();
+    assert_eq!(m.thread, Some(thread));
+    assert_eq!(m.comment, Some(comment));
+    assert!(!ob.comment_acknowledged(&comment, &you));
+    ob.acknowledge(&you, &ids[1]).unwrap();
+    assert!(ob.comment_acknowledged(&comment, &you));
+    assert!(!ob.comment_acknowledged(&comment, &them));
+}
@@ -7,15 +7,18 @@ use onionsalt::crypto;
 use std;
 use std::collections::HashMap;
 use dht;
-use dht::{UserMessage, EncryptedMessage,
-          MyBytes, DECRYPTED_USER_MESSAGE_LENGTH, USER_MESSAGE_LENGTH};
+use dht::{MyBytes, FallibleBytes, DECRYPTED_USER_MESSAGE_LENGTH, USER_MESSAGE_LENGTH};
 use message;
-use onionsalt::{PAYLOAD_LENGTH};
-
-use std::sync::mpsc::{ Receiver, SyncSender,
-                       Sender, };
+use error::{Error};
+use udp;
 
 use str255::{Str255};
+use outbox::{Outbox, RetryPolicy};
+use mailbox::{Mailbox};
+use secret::SecretKeyPair;
+use session::Sessions;
+use format;
+use format::{DeliveryState};
 use serde;
 
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
@@ -78,6 +81,14 @@ fn sixteen_hex_to_u64(bytes: &[u8;16]) -> Option<u64> {
     Some(out)
 }
 
+/// The most recipients that a single `ThreadRecipients` can list.
+pub const MAX_RECIPIENTS: usize = 9;
+
+/// The number of bytes of text that fit in a single `Comment`.
+/// Longer comments are split into several `Comment` messages by
+/// `comment_fragments`.
+pub const COMMENT_LENGTH: usize = 394;
+
 pub enum Message {
     UserQuery {
         user: Str255
@@ -91,12 +102,12 @@ pub enum Message {
         time: u32,
         message_length: u32,
         message_start: u32, // for long messages!
-        contents: [u8; 394],
+        contents: [u8; COMMENT_LENGTH],
     },
     ThreadRecipients {
         thread: Thread,
         num_recipients: u8,
-        recipients: [crypto::PublicKey; 9],
+        recipients: [crypto::PublicKey; MAX_RECIPIENTS],
     },
     ThreadSubject {
         thread: Thread,
@@ -105,11 +116,52 @@ pub enum Message {
     Acknowledge {
         msg_id: message::Id,
     },
+    /// The sender's newest session key, which we should use in
+    /// messages to them from now on.  See `session`.
+    Rekey {
+        key: crypto::PublicKey,
+    },
+    /// A message of a kind we do not understand, presumably from a
+    /// newer client.
+    Unknown {
+        tag: u8,
+    },
 }
 impl Message {
+    /// A `ThreadSubject` naming `thread`.  Subjects longer than 80
+    /// bytes are truncated.
+    pub fn thread_subject(thread: Thread, subject: &str) -> Message {
+        let b = subject.as_bytes();
+        let mut s = [0u8; 80];
+        for i in 0 .. std::cmp::min(b.len(), s.len()) {
+            s[i] = b[i];
+        }
+        Message::ThreadSubject { thread: thread, subject: s }
+    }
+    /// A `ThreadRecipients` listing the participants in `thread`.
+    /// Only the first `MAX_RECIPIENTS` are included.
+    pub fn thread_recipients(thread: Thread, who: &[crypto::PublicKey]) -> Message {
+        let n = std::cmp::min(who.len(), MAX_RECIPIENTS);
+        let mut recipients = [crypto::PublicKey([0;32]); MAX_RECIPIENTS];
+        for i in 0 .. n {
+            recipients[i] = who[i];
+        }
+        Message::ThreadRecipients {
+            thread: thread,
+            num_recipients: n as u8,
+            recipients: recipients,
+        }
+    }
     fn needs_acknowledgement(&self) -> bool {
         match *self {
             Message::Comment {..} | Message::ThreadSubject {..} | Message::ThreadRecipients {..} => true,
+            // Until a Rekey is acknowledged, we cannot count on them
+            // knowing our session key.
+            Message::Rekey {..} => true,
+            // We cannot tell whether an unknown message wants an
+            // acknowledgement, so we give it one rather than have the
+            // sender retry it until it gives up.
+            Message::Unknown {..} => true,
             _ => false,
         }
     }
@@ -126,6 +178,8 @@ impl Clone for Message {
                 ThreadRecipients {thread:thread,num_recipients:num_recipients,recipients:recipients},
             ThreadSubject {thread,subject} => ThreadSubject {thread:thread,subject:subject},
             Acknowledge {msg_id} => Acknowledge {msg_id:msg_id},
+            Rekey {key} => Rekey {key:key},
+            Unknown {tag} => Unknown {tag:tag},
         }
     }
 }
@@ -143,6 +197,14 @@ impl std::fmt::Debug for Message {
                 f.write_str(&format!("Comment({:x}, {}, {}, {}, ...)",
                                      thread.0, time, message_length, message_start))
             },
+            &Message::ThreadRecipients { ref thread, ref num_recipients, .. } => {
+                f.write_str(&format!("ThreadRecipients({:x}, {}, ...)",
+                                     thread.0, num_recipients))
+            },
+            &Message::ThreadSubject { ref thread, ref subject } => {
+                f.write_str(&format!("ThreadSubject({:x}, {})",
+                                     thread.0, String::from_utf8_lossy(subject)))
+            },
             &Message::Acknowledge { ref msg_id } => {
                 if *msg_id == message::Id([0;32]) && false {
                     f.write_str(&format!("<invalid Message>"))
@@ -150,59 +212,150 @@ impl std::fmt::Debug for Message {
                     f.write_str(&format!("Acknowledge({})", msg_id))
                 }
             },
-            _ => {
-                f.write_str("<unhandled?>")
+            &Message::Rekey { ref key } => {
+                f.write_str(&format!("Rekey({})", key))
+            },
+            &Message::Unknown { ref tag } => {
+                f.write_str(&format!("Unknown({})", tag))
             },
         }
     }
 }
-impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
-    fn bytes(&self, out: &mut[u8; DECRYPTED_USER_MESSAGE_LENGTH]) {
+/// The version of the wire format we write.  The plaintext of a
+/// message is laid out as `[version, tag, payload...]`.  The original
+/// format had no version byte, and started directly with the tag,
+/// which we call version 0.  Since every tag is an ASCII lowercase
+/// letter, and versions are small numbers, the two are easily told
+/// apart.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The size of the payload in the version 0 format, which is the
+/// layout we decode into.  Current payloads are one byte shorter, and
+/// are zero-padded to this length.
+const LEGACY_PAYLOAD_LENGTH: usize = DECRYPTED_USER_MESSAGE_LENGTH - 1;
+
+/// The longest comment we send, or reassemble, in bytes.  This must
+/// stay below 2^24; see `COMMENT_SPARE_BYTE`.
+pub const MAX_COMMENT_LENGTH: u32 = 1 << 20;
+
+/// Where the high byte of a `Comment`'s `message_start` lies in its
+/// payload.  Since comments are shorter than `MAX_COMMENT_LENGTH`,
+/// this byte is always zero, and the current format stores the last
+/// byte of the contents there instead, as a whole comment payload is
+/// one byte too long to fit.
+const COMMENT_SPARE_BYTE: usize = 8 + 4 + 4 + 3;
+
+fn is_legacy_tag(b: u8) -> bool {
+    b >= b'a' && b <= b'z'
+}
+
+impl Message {
+    fn tag(&self) -> u8 {
+        match *self {
+            Message::UserQuery {..} => b'q',
+            Message::UserResponse {..} => b'r',
+            Message::Comment {..} => b'c',
+            Message::ThreadRecipients {..} => b'p',
+            Message::ThreadSubject {..} => b's',
+            Message::Acknowledge {..} => b'a',
+            Message::Rekey {..} => b'k',
+            Message::Unknown { tag } => tag,
+        }
+    }
+    fn payload_bytes(&self, out: &mut [u8; LEGACY_PAYLOAD_LENGTH]) {
         match *self {
             Message::UserQuery { ref user } => {
-                out[0] = b'q';
-                user.bytes(array_mut_ref![out,1,256]);
+                user.bytes(array_mut_ref![out,0,256]);
             },
             Message::UserResponse { ref user, ref key } => {
-                let (z, u, k, _) = mut_array_refs!(out, 1, 256, 32, 126);
-                z[0] = b'r';
+                let (u, k, _) = mut_array_refs!(out, 256, 32, 126);
                 user.bytes(u);
                 key.bytes(k);
             },
             Message::Comment { ref thread, ref time, ref message_length,
                                ref message_start, ref contents } => {
-                let (z, t, cid, ml, ms, c) = mut_array_refs!(out, 1, 8, 4, 4, 4, 394);
-                z[0] = b'c';
+                let (t, cid, ml, ms, c) = mut_array_refs!(out, 8, 4, 4, 4, COMMENT_LENGTH);
                 thread.0.bytes(t);
                 time.bytes(cid);
                 message_length.bytes(ml);
                 message_start.bytes(ms);
                 *c = *contents;
             },
+            Message::ThreadRecipients { ref thread, ref num_recipients, ref recipients } => {
+                let (t, n, rs, _) = mut_array_refs!(out, 8, 1, 32*MAX_RECIPIENTS, 117);
+                thread.0.bytes(t);
+                n[0] = *num_recipients;
+                for i in 0 .. MAX_RECIPIENTS {
+                    recipients[i].bytes(array_mut_ref![rs, 32*i, 32]);
+                }
+            },
+            Message::ThreadSubject { ref thread, ref subject } => {
+                let (t, s, _) = mut_array_refs!(out, 8, 80, 326);
+                thread.0.bytes(t);
+                *s = *subject;
+            },
             Message::Acknowledge { ref msg_id } => {
-                let (z, id, _) = mut_array_refs!(out, 1, 32, 382);
-                z[0] = b'a';
-                msg_id.bytes(id);
+                msg_id.bytes(array_mut_ref![out,0,32]);
             },
-            _ => {
-                *out = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+            Message::Rekey { ref key } => {
+                key.bytes(array_mut_ref![out,0,32]);
             },
+            Message::Unknown { .. } => (),
         }
     }
-    fn from_bytes(inp: &[u8; DECRYPTED_USER_MESSAGE_LENGTH]) -> Message {
-        match inp[0] {
+    /// Encode this message in the version 0 format, for the benefit
+    /// of peers that do not yet understand the current one.  This
+    /// fails for messages that did not exist in version 0.
+    pub fn legacy_bytes(&self, out: &mut [u8; DECRYPTED_USER_MESSAGE_LENGTH]) -> Result<(), Error> {
+        match *self {
+            Message::Unknown { .. } | Message::Rekey { .. } => {
+                return Err(Error::MalformedPacket);
+            },
+            _ => (),
+        }
+        let (z, p) = mut_array_refs!(out, 1, LEGACY_PAYLOAD_LENGTH);
+        z[0] = self.tag();
+        self.payload_bytes(p);
+        Ok(())
+    }
+    /// Decode a message in either the current or the version 0
+    /// format, also returning the version it was written in.  Tags
+    /// we do not recognize, and messages in any version newer than
+    /// ours, decode to `Unknown`, so that messages from newer clients
+    /// are never mistaken for something else.
+    pub fn decode(inp: &[u8; DECRYPTED_USER_MESSAGE_LENGTH]) -> Result<(u8, Message), Error> {
+        let mut p = [0; LEGACY_PAYLOAD_LENGTH];
+        let (version, tag) = if is_legacy_tag(inp[0]) {
+            p = *array_ref![inp, 1, LEGACY_PAYLOAD_LENGTH];
+            (0, inp[0])
+        } else if inp[0] == 0 || inp[1] == 0 {
+            // Neither version 0 nor tag 0 is ever written explicitly,
+            // so this is garbage (or an all-zero pickup request).
+            return Err(Error::MalformedPacket);
+        } else if inp[0] > PROTOCOL_VERSION {
+            // We cannot know how a newer version lays out its payload.
+            return Ok((inp[0], Message::Unknown { tag: inp[1] }));
+        } else {
+            *array_mut_ref![p, 0, LEGACY_PAYLOAD_LENGTH-1] = *array_ref![inp, 2, LEGACY_PAYLOAD_LENGTH-1];
+            if inp[1] == b'c' {
+                p[LEGACY_PAYLOAD_LENGTH-1] = p[COMMENT_SPARE_BYTE];
+                p[COMMENT_SPARE_BYTE] = 0;
+            }
+            (inp[0], inp[1])
+        };
+        let m = match tag {
             b'q' => Message::UserQuery {
-                user: Str255::from_bytes(array_ref![inp,1,256]),
+                user: Str255::from_bytes(array_ref![p,0,256]),
             },
             b'r' => {
-                let (_, u, k, _) = array_refs!(inp, 1, 256, 32, 126);
+                let (u, k, _) = array_refs!(&p, 256, 32, 126);
                 Message::UserResponse {
                     user: Str255::from_bytes(u),
                     key: crypto::PublicKey::from_bytes(k),
                 }
             },
             b'c' => {
-                let (_, t, cid, ml, ms, c) = array_refs!(inp, 1, 8, 4, 4, 4, 394);
+                let (t, cid, ml, ms, c) = array_refs!(&p, 8, 4, 4, 4, COMMENT_LENGTH);
                 Message::Comment {
                     thread: Thread(u64::from_bytes(t)),
                     time: u32::from_bytes(cid),
@@ -211,13 +364,85 @@ impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
                     contents: *c,
                 }
             },
-            b'a' => {
-                let (_, id, _) = array_refs!(inp, 1, 32, 382);
-                Message::Acknowledge {
-                    msg_id: message::Id::from_bytes(id),
+            b'p' => {
+                let (t, n, rs, _) = array_refs!(&p, 8, 1, 32*MAX_RECIPIENTS, 117);
+                let mut recipients = [crypto::PublicKey([0;32]); MAX_RECIPIENTS];
+                for i in 0 .. MAX_RECIPIENTS {
+                    recipients[i] = crypto::PublicKey::from_bytes(array_ref![rs, 32*i, 32]);
+                }
+                Message::ThreadRecipients {
+                    thread: Thread(u64::from_bytes(t)),
+                    num_recipients: n[0],
+                    recipients: recipients,
                 }
             },
-            _ => Message::Acknowledge { msg_id: message::Id([0;32]) }
+            b's' => {
+                let (t, s, _) = array_refs!(&p, 8, 80, 326);
+                Message::ThreadSubject {
+                    thread: Thread(u64::from_bytes(t)),
+                    subject: *s,
+                }
+            },
+            b'a' => Message::Acknowledge {
+                msg_id: message::Id::from_bytes(array_ref![p,0,32]),
+            },
+            b'k' => Message::Rekey {
+                key: crypto::PublicKey::from_bytes(array_ref![p,0,32]),
+            },
+            _ => Message::Unknown { tag: tag },
+        };
+        Ok((version, m))
+    }
+}
+impl FallibleBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
+    fn bytes(&self, out: &mut[u8; DECRYPTED_USER_MESSAGE_LENGTH]) {
+        let mut p = [0; LEGACY_PAYLOAD_LENGTH];
+        self.payload_bytes(&mut p);
+        if let Message::Comment { message_start, .. } = *self {
+            // Otherwise we would overwrite part of message_start.
+            assert!(message_start < MAX_COMMENT_LENGTH, "comment fragment starts too late");
+            p[COMMENT_SPARE_BYTE] = p[LEGACY_PAYLOAD_LENGTH-1];
+        }
+        let (v, z, rest) = mut_array_refs!(out, 1, 1, LEGACY_PAYLOAD_LENGTH-1);
+        v[0] = PROTOCOL_VERSION;
+        z[0] = self.tag();
+        *rest = *array_ref![p, 0, LEGACY_PAYLOAD_LENGTH-1];
+    }
+    fn from_bytes(inp: &[u8; DECRYPTED_USER_MESSAGE_LENGTH]) -> Result<Message, Error> {
+        Message::decode(inp).map(|(_, m)| { m })
+    }
+}
+
+/// Split a comment into as many `Comment` messages as are needed to
+/// carry it.  Every fragment holds the total length of the comment in
+/// `message_length` and its own byte offset in `message_start`, so the
+/// fragments can be reassembled in any order.  Note that a fragment
+/// boundary may fall in the middle of a UTF-8 character, so only the
+/// reassembled comment should be decoded as text.  A body longer than
+/// `MAX_COMMENT_LENGTH` is refused.
+pub fn comment_fragments(thread: Thread, time: u32, body: &str) -> Result<Vec<Message>, Error> {
+    let bytes = body.as_bytes();
+    if bytes.len() > MAX_COMMENT_LENGTH as usize {
+        return Err(Error::TooLong);
+    }
+    let mut out = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = std::cmp::min(start + COMMENT_LENGTH, bytes.len());
+        let mut contents = [0u8; COMMENT_LENGTH];
+        for i in start .. end {
+            contents[i - start] = bytes[i];
+        }
+        out.push(Message::Comment {
+            thread: thread,
+            time: time,
+            message_length: bytes.len() as u32,
+            message_start: start as u32,
+            contents: contents,
+        });
+        start = end;
+        if start >= bytes.len() {
+            return Ok(out);
         }
     }
 }
@@ -226,7 +451,7 @@ impl MyBytes<[u8; DECRYPTED_USER_MESSAGE_LENGTH]> for Message {
 fn test_message(m: Message) {
     let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
     m.bytes(&mut buf);
-    let newm = Message::from_bytes(&buf);
+    let newm = Message::from_bytes(&buf).unwrap();
     let mut buf2 = [0; DECRYPTED_USER_MESSAGE_LENGTH];
     newm.bytes(&mut buf2);
     for i in 0 .. buf.len() {
@@ -248,6 +473,130 @@ fn response_bytes() {
     });
 }
 #[test]
+fn thread_bytes() {
+    test_message(Message::thread_subject(Thread(5), "a subject"));
+    let who = [crypto::box_keypair().public, crypto::box_keypair().public];
+    test_message(Message::thread_recipients(Thread(5), &who));
+    let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+    Message::thread_recipients(Thread(5), &who).bytes(&mut buf);
+    match Message::from_bytes(&buf).unwrap() {
+        Message::ThreadRecipients { thread, num_recipients, recipients } => {
+            assert_eq!(thread, Thread(5));
+            assert_eq!(num_recipients, 2);
+            assert_eq!(&recipients[0..2], &who[..]);
+        },
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+}
+#[test]
+fn fragments() {
+    let short = comment_fragments(Thread(1), 7, "hello").unwrap();
+    assert_eq!(short.len(), 1);
+    let long: String = std::iter::repeat("ü").take(COMMENT_LENGTH+1).collect();
+    let frags = comment_fragments(Thread(1), 7, &long).unwrap();
+    assert_eq!(frags.len(), 3);
+    let mut reassembled = Vec::new();
+    for f in frags.iter() {
+        test_message(f.clone());
+        if let Message::Comment { message_length, message_start, contents, .. } = *f {
+            assert_eq!(message_length as usize, long.len());
+            assert_eq!(message_start as usize, reassembled.len());
+            let len = std::cmp::min(COMMENT_LENGTH, message_length as usize - message_start as usize);
+            reassembled.extend(contents[0 .. len].iter().cloned());
+        } else {
+            panic!("fragment is not a comment");
+        }
+    }
+    assert_eq!(&reassembled[..], long.as_bytes());
+
+    // The last fragment of the longest comment still leaves the high
+    // byte of message_start free, but anything longer is refused.
+    let longest: String = std::iter::repeat("x").take(MAX_COMMENT_LENGTH as usize).collect();
+    let frags = comment_fragments(Thread(1), 7, &longest).unwrap();
+    test_message(frags[frags.len() - 1].clone());
+    let too_long: String = std::iter::repeat("x").take(MAX_COMMENT_LENGTH as usize + 1).collect();
+    assert!(comment_fragments(Thread(1), 7, &too_long).is_err());
+}
+#[test]
+fn malformed_bytes() {
+    let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+    assert!(Message::from_bytes(&buf).is_err());
+    buf[0] = PROTOCOL_VERSION;
+    assert!(Message::from_bytes(&buf).is_err());
+    buf[1] = b'z';
+    match Message::from_bytes(&buf).unwrap() {
+        Message::Unknown { tag } => assert_eq!(tag, b'z'),
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+    buf[0] = b'z';
+    match Message::decode(&buf).unwrap() {
+        (0, Message::Unknown { tag }) => assert_eq!(tag, b'z'),
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+    // A newer version may lay out even familiar messages differently.
+    Message::Acknowledge { msg_id: message::Id::random() }.bytes(&mut buf);
+    buf[0] = PROTOCOL_VERSION + 1;
+    match Message::decode(&buf).unwrap() {
+        (v, Message::Unknown { tag }) => {
+            assert_eq!(v, PROTOCOL_VERSION + 1);
+            assert_eq!(tag, b'a');
+        },
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+}
+#[test]
+fn legacy_bytes() {
+    let frags = comment_fragments(Thread(3), 9, "hello world").unwrap();
+    let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+    frags[0].legacy_bytes(&mut buf).unwrap();
+    assert_eq!(buf[0], b'c');
+    match Message::decode(&buf).unwrap() {
+        (0, Message::Comment { thread, time, message_length, contents, .. }) => {
+            assert_eq!(thread, Thread(3));
+            assert_eq!(time, 9);
+            assert_eq!(&contents[0 .. message_length as usize], b"hello world");
+        },
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+    frags[0].bytes(&mut buf);
+    assert_eq!(buf[0], PROTOCOL_VERSION);
+    assert_eq!(Message::decode(&buf).unwrap().0, PROTOCOL_VERSION);
+    // Fragments that fill every byte, as old clients send them,
+    // survive in either format.
+    buf = [b'x'; DECRYPTED_USER_MESSAGE_LENGTH];
+    buf[0] = b'c';
+    let long = [0, 0, 1, 0]; // a message_length of 65536
+    *array_mut_ref![buf, 1+8+4, 4] = long;
+    *array_mut_ref![buf, 1+8+4+4, 4] = [0; 4];
+    let m = match Message::decode(&buf).unwrap() {
+        (0, m) => m,
+        m => panic!("decoded the wrong message: {:?}", m),
+    };
+    m.bytes(&mut buf);
+    match Message::decode(&buf).unwrap() {
+        (PROTOCOL_VERSION, Message::Comment { message_length, message_start, contents, .. }) => {
+            assert_eq!(message_length, 65536);
+            assert_eq!(message_start, 0);
+            assert!(contents.iter().all(|&b| { b == b'x' }));
+        },
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+    assert!(Message::Unknown { tag: b'z' }.legacy_bytes(&mut buf).is_err());
+}
+#[test]
+fn rekey_bytes() {
+    let key = crypto::box_keypair().public;
+    test_message(Message::Rekey { key: key });
+    let mut buf = [0; DECRYPTED_USER_MESSAGE_LENGTH];
+    Message::Rekey { key: key }.bytes(&mut buf);
+    match Message::from_bytes(&buf).unwrap() {
+        Message::Rekey { key: k } => assert_eq!(k, key),
+        m => panic!("decoded the wrong message: {:?}", m),
+    }
+    // Version 0 clients would not understand it.
+    assert!(Message::Rekey { key: key }.legacy_bytes(&mut buf).is_err());
+}
+#[test]
 fn acknowledge_bytes() {
     let k = crypto::box_keypair();
     let id = message::Id(k.public.0);
@@ -266,18 +615,117 @@ fn acknowledge_bytes() {
     });
 }
 
-pub fn read_key(name: &std::path::Path) -> Result<crypto::PublicKey, std::io::Error> {
+pub fn read_key(name: &std::path::Path) -> Result<crypto::PublicKey, Error> {
     use std::io::Read;
 
     let mut f = try!(std::fs::File::open(name));
     let mut data = Vec::new();
     try!(f.read_to_end(&mut data));
     if data.len() != 32 {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "oh no!"));
+        return Err(Error::BadKeyFile);
     }
     Ok(crypto::PublicKey(*array_ref![data, 0, 32]))
 }
 
+/// The number of recently received messages whose `message::Id` we
+/// remember, so that retransmissions are recognized as duplicates.
+const NUM_SEEN: usize = 1024;
+
+/// The least time in seconds between our acknowledgements of the same
+/// message.  Copies from the other rendezvous arrive within moments of
+/// each other, while the sender waits longer than this before
+/// retransmitting.
+const MIN_REACK_INTERVAL: u32 = 20;
+
+/// The `message::Id`s of the messages we have most recently received,
+/// stored on disk so that duplicates are recognized even after a
+/// restart.
+struct Seen {
+    name: std::path::PathBuf,
+    order: std::collections::VecDeque<message::Id>,
+    ids: std::collections::HashSet<message::Id>,
+    /// When we last acknowledged each message, in seconds since
+    /// `udp::EPOCH`.
+    acked: HashMap<message::Id, u32>,
+}
+
+impl Seen {
+    fn read(the_dir: &std::path::PathBuf) -> Seen {
+        let mut name = the_dir.clone();
+        name.push("seen");
+        let order: Vec<message::Id> = format::read_json(&name).unwrap_or(Vec::new());
+        Seen {
+            name: name,
+            ids: order.iter().cloned().collect(),
+            order: order.into_iter().collect(),
+            acked: HashMap::new(),
+        }
+    }
+    fn contains(&self, msg_id: &message::Id) -> bool {
+        self.ids.contains(msg_id)
+    }
+    fn insert(&mut self, msg_id: message::Id) -> Result<(), std::io::Error> {
+        if !self.ids.insert(msg_id) {
+            return Ok(());
+        }
+        self.order.push_back(msg_id);
+        while self.order.len() > NUM_SEEN {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+                self.acked.remove(&old);
+            }
+        }
+        let order: Vec<message::Id> = self.order.iter().cloned().collect();
+        format::write_json(&self.name, &order)
+    }
+    /// Note that `msg_id` is acknowledged at time `now`.  Returns
+    /// false, and notes nothing, if we already acknowledged it less
+    /// than `MIN_REACK_INTERVAL` ago.
+    fn acknowledge(&mut self, msg_id: &message::Id, now: u32) -> bool {
+        if let Some(&last) = self.acked.get(msg_id) {
+            if now < last.saturating_add(MIN_REACK_INTERVAL) {
+                return false;
+            }
+        }
+        self.acked.insert(*msg_id, now);
+        true
+    }
+}
+
+#[test]
+fn test_seen() {
+    let dir = format::test_dir();
+    let ids: Vec<_> = (0 .. NUM_SEEN+1).map(|_| { message::Id::random() }).collect();
+    {
+        let mut seen = Seen::read(&dir);
+        assert!(!seen.contains(&ids[0]));
+        for id in ids.iter() {
+            seen.insert(*id).unwrap();
+        }
+        seen.insert(ids[NUM_SEEN]).unwrap();
+    }
+    let seen = Seen::read(&dir);
+    assert!(!seen.contains(&ids[0]));
+    assert!(seen.contains(&ids[1]));
+    assert!(seen.contains(&ids[NUM_SEEN]));
+    assert_eq!(seen.order.len(), NUM_SEEN);
+}
+
+#[test]
+fn test_reacknowledge() {
+    let dir = format::test_dir();
+    let mut seen = Seen::read(&dir);
+    let id = message::Id::random();
+    seen.insert(id).unwrap();
+    assert!(seen.acknowledge(&id, 1000));
+    // Copies from the other rendezvous are not acknowledged again...
+    assert!(!seen.acknowledge(&id, 1001));
+    assert!(!seen.acknowledge(&id, 1000 + MIN_REACK_INTERVAL - 1));
+    // ...but a later retransmission is.
+    assert!(seen.acknowledge(&id, 1000 + MIN_REACK_INTERVAL));
+    assert!(!seen.acknowledge(&id, 1000 + MIN_REACK_INTERVAL));
+}
+
 pub struct AddressBook {
     /// These are keys that we are willing to share with others who
     /// might query regarding them.  i.e. we are unashamed that we
@@ -286,12 +734,25 @@ pub struct AddressBook {
     /// These are keys that we do not want to share.  Secret
     /// identities or alter egos, etc.
     secret_ids: HashMap<String, crypto::PublicKey>,
-    unacknowledged: HashMap<message::Id, (crypto::PublicKey, [u8;USER_MESSAGE_LENGTH])>,
-    myself: crypto::KeyPair,
-    hear_rendezvous: Receiver<crypto::PublicKey>,
-    ask_rendezvous: SyncSender<crypto::PublicKey>,
-    message_sender: Sender<EncryptedMessage>,
-    message_receiver: Receiver<UserMessage>,
+    /// The messages we have sent that still need acknowledging.
+    outbox: Outbox,
+    /// The messages we have recently received.
+    seen: Seen,
+    /// The peers that have sent us messages in the version 0 wire
+    /// format, and to whom we therefore reply in kind.
+    legacy_peers: std::collections::HashSet<crypto::PublicKey>,
+    myself: SecretKeyPair,
+    /// Our session keys, and those our contacts have told us.
+    sessions: Sessions,
+    /// Whether we use session keys with the contacts who support
+    /// them.
+    forward_secrecy: bool,
+    /// The `time` of the last comment we sent, which we never reuse,
+    /// since our recipients tell the fragments of different comments
+    /// apart by their sender, thread, `time` and length.
+    last_comment_time: u32,
+    /// Our node, which is shut down when we are dropped.
+    node: dht::Node,
     dir: std::path::PathBuf,
 }
 
@@ -391,146 +852,383 @@ impl AddressBook {
         Ok((public_dir, secret_dir))
     }
 
-    pub fn rendezvous(&self, k: &crypto::PublicKey) -> crypto::PublicKey {
-        self.ask_rendezvous.send(*k).unwrap();
-        self.hear_rendezvous.recv().unwrap()
+    /// The nodes at which messages for `k` are deposited, closest
+    /// first.
+    pub fn rendezvous(&self, k: &crypto::PublicKey) -> Result<Vec<crypto::PublicKey>, Error> {
+        self.node.rendezvous(k)
+    }
+    /// The state of our node.
+    pub fn status(&self) -> Result<dht::NodeStatus, Error> {
+        self.node.status()
     }
 
     pub fn send(&mut self, who: &crypto::PublicKey, msg: &Message) -> message::Id {
+        let is_rekey = match *msg { Message::Rekey {..} => true, _ => false };
+        if self.forward_secrecy && !is_rekey && *who != self.myself.public()
+            && !self.legacy_peers.contains(who) {
+            if let Some(key) = self.sessions.untold(who) {
+                // We tell them our session key before anything else,
+                // so that they can use it in their reply.  They count
+                // as told once they acknowledge it.
+                let rekey_id = self.send(who, &Message::Rekey { key: key });
+                self.sessions.telling(who, rekey_id);
+            }
+        }
         let mut plaintext = [0u8; DECRYPTED_USER_MESSAGE_LENGTH];
-        msg.bytes(&mut plaintext);
-        let (msg_id, c) = dht::double_box(&plaintext, who, &self.myself);
+        if !self.legacy_peers.contains(who) || msg.legacy_bytes(&mut plaintext).is_err() {
+            msg.bytes(&mut plaintext);
+        }
+        // A Rekey is sealed with our long-term keys, since they cannot
+        // open anything sealed with the key it announces.
+        let (msg_id, c) = self.sessions.seal(&plaintext, who, &self.myself,
+                                             self.forward_secrecy && !is_rekey);
         // info!(" ****** \"{}\" ****** {} ******", dht::codename(&c),
         //       dht::codename(&c[32+24 .. 32+24+6]));
 
-        self.send_doubleboxed(who, &msg_id, &c);
-
         if msg.needs_acknowledgement() {
-            self.unacknowledged.insert(msg_id, (*who, c));
+            if let Err(e) = self.outbox.insert(msg_id, who, &c, format::DateRfc3339::now()) {
+                info!("Unable to save message {} in outbox: {}", dht::codename(&msg_id.0), e);
+            }
+        }
 
+        if let Err(e) = self.send_doubleboxed(who, &msg_id, &c) {
+            info!("Unable to send message {}: {}", dht::codename(&msg_id.0), e);
+            return msg_id;
+        }
+
+        if msg.needs_acknowledgement() {
+            if let Err(e) = self.outbox.mark_sent(&msg_id, format::DateRfc3339::now()) {
+                info!("Unable to update outbox: {}", e);
+            }
             let mut q = String::new();
-            for k in self.unacknowledged.keys() {
+            for (k, _, _) in self.outbox.unacknowledged() {
                 q = format!("{} '{}'", q, dht::codename(&k.0));
             }
             info!("Messages in queue: {}", q);
         }
         msg_id
     }
-    pub fn send_doubleboxed(&mut self, who: &crypto::PublicKey, msg_id: &message::Id, c: &[u8;USER_MESSAGE_LENGTH]) {
-        let ren = self.rendezvous(who);
-
-        let mut p = [0; PAYLOAD_LENGTH];
-        dht::Message::ForwardPlease {
-            destination: *who,
-            message: *c,
-        }.bytes(&mut p);
-
-        info!("Sent message {}", dht::codename(&msg_id.0));
-        self.message_sender.send(EncryptedMessage {
-            rendezvous: ren,
-            contents: p,
-        }).unwrap();
-    }
-
-    pub fn pickup(&mut self) {
-        let ren = self.rendezvous(&self.myself.public);
-        // info!("   ═══ Sending pickup request to {}! ═══", ren);
-        let msg = [0; DECRYPTED_USER_MESSAGE_LENGTH];
-        let (_, c) = dht::double_box(&msg, &ren, &self.myself);
-        // info!("  E {} size {}", dht::codename(&c), c.len());
-
-        let mut p = [0; PAYLOAD_LENGTH];
-        dht::Message::PickUp {
-            destination: self.myself.public,
-            message: c,
-        }.bytes(&mut p);
-
-        self.message_sender.send(EncryptedMessage {
-            rendezvous: ren,
-            contents: p,
-        }).unwrap();
-
-        let num_unacknowledged = self.unacknowledged.len();
-        if num_unacknowledged > 0 {
-            // The following is a ridiculous contortion to get around
-            // the borrow checker.  This is one trouble with grouping
-            // several data structures into an object in rust.  A
-            // borrow on any is a borrow on all.  :( To quote the
-            // Fullmetal Alchemist: One is all, and all is one.
-            let somev: Option<(message::Id, crypto::PublicKey, [u8;USER_MESSAGE_LENGTH])> = {
-                let somev = self.unacknowledged.iter().nth(crypto::random_u32() as usize % num_unacknowledged);
-                match somev {
-                    None => None,
-                    Some((a,b)) => Some((a.clone(),b.0.clone(),b.1)),
+    /// How far along the delivery of a message we sent has come.
+    /// Returns `None` for messages that need no acknowledgement, or
+    /// that we did not send.
+    pub fn delivery_state(&self, msg_id: &message::Id) -> Option<DeliveryState> {
+        self.outbox.state(msg_id)
+    }
+    /// The messages we have given up trying to deliver since the last
+    /// call, so that the user can be told about them.
+    pub fn take_failures(&mut self) -> Vec<message::Id> {
+        self.outbox.take_failures()
+    }
+    /// Change how often unacknowledged messages are resent, and when
+    /// we give up on them.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.outbox.set_policy(policy);
+    }
+    /// Send `msg` to everyone in `recipients`.  Each recipient gets a
+    /// separately `double_box`ed copy with its own `message::Id`, and
+    /// each copy awaits its own acknowledgement, so that one silent
+    /// recipient does not hold up delivery to the others.  We do not
+    /// send a copy to ourselves unless we are the only recipient.
+    pub fn send_to_all(&mut self, recipients: &[crypto::PublicKey], msg: &Message)
+                       -> Vec<(crypto::PublicKey, message::Id)> {
+        let me = self.myself.public();
+        let mut who: Vec<crypto::PublicKey> = Vec::new();
+        for k in recipients {
+            if *k != me && !who.contains(k) {
+                who.push(*k);
+            }
+        }
+        if who.len() == 0 && recipients.contains(&me) {
+            who.push(me);
+        }
+        let mut out = Vec::new();
+        for k in who {
+            let msg_id = self.send(&k, msg);
+            out.push((k, msg_id));
+        }
+        out
+    }
+    /// Send a comment of any length to everyone in `recipients`,
+    /// splitting it into as many `Comment` messages as needed.
+    /// Returns each fragment along with a `message::Id` (that of the
+    /// first recipient's copy), so that it can be saved in our own
+    /// `Mailbox`.  Fails without sending anything if the comment is
+    /// too long.
+    pub fn send_comment(&mut self, recipients: &[crypto::PublicKey], thread: Thread, body: &str)
+                        -> Result<Vec<(message::Id, Message)>, Error> {
+        let time = std::cmp::max(udp::epoch_time(), self.last_comment_time.wrapping_add(1));
+        let fragments = try!(comment_fragments(thread, time, body));
+        self.last_comment_time = time;
+        let mut out = Vec::new();
+        let mut first = None;
+        for m in fragments {
+            let copies = self.send_to_all(recipients, &m);
+            let msg_id = match copies.first() {
+                Some(&(_, msg_id)) => msg_id,
+                None => message::Id::random(),
+            };
+            // The whole comment is stored in the mailbox under the id
+            // of its first fragment.
+            let comment = match first {
+                Some(c) => c,
+                None => {
+                    first = Some(msg_id);
+                    msg_id
+                },
+            };
+            for &(_, copy_id) in copies.iter() {
+                if let Err(e) = self.outbox.set_comment(&copy_id, thread, comment) {
+                    info!("Unable to update outbox: {}", e);
                 }
+            }
+            out.push((msg_id, m));
+        }
+        Ok(out)
+    }
+    /// Tell everyone in `recipients` that they are part of `thread`,
+    /// and optionally what its subject is.  The returned messages
+    /// should be saved in our own `Mailbox`.  We list ourselves first,
+    /// so that we are never the one left out when there are more than
+    /// `MAX_RECIPIENTS` participants.
+    pub fn announce_thread(&mut self, thread: Thread, recipients: &[crypto::PublicKey],
+                           subject: Option<&str>) -> Vec<(message::Id, Message)> {
+        let me = self.myself.public();
+        let mut everyone = vec![me];
+        everyone.extend(recipients.iter().filter(|&&k| { k != me }).cloned());
+        if everyone.len() > MAX_RECIPIENTS {
+            info!("Only the first {} of the {} participants in {} will be listed",
+                  MAX_RECIPIENTS, everyone.len(), thread);
+        }
+        let mut msgs = vec![Message::thread_recipients(thread, &everyone)];
+        if let Some(subject) = subject {
+            msgs.push(Message::thread_subject(thread, subject));
+        }
+        let mut out = Vec::new();
+        for m in msgs {
+            let msg_id = match self.send_to_all(&everyone, &m).first() {
+                Some(&(_, msg_id)) => msg_id,
+                None => message::Id::random(),
             };
-            if let Some(v) = somev {
-                info!("I am going to retry...");
-                self.send_doubleboxed(&v.1,&v.0,&v.2);
+            out.push((msg_id, m));
+        }
+        out
+    }
+    pub fn send_doubleboxed(&mut self, who: &crypto::PublicKey, msg_id: &message::Id,
+                            c: &[u8;USER_MESSAGE_LENGTH]) -> Result<(), Error> {
+        // We leave a copy with each rendezvous, and the recipient
+        // ignores any duplicates by their `message::Id`.
+        try!(self.node.send_ciphertext(who, c));
+        info!("Sent message {}", dht::codename(&msg_id.0));
+        Ok(())
+    }
+
+    /// Stop (or start) using session keys in the messages we send.
+    /// We can always read messages that use them.
+    pub fn set_forward_secrecy(&mut self, on: bool) {
+        self.forward_secrecy = on;
+    }
+    /// Change how often we replace our session key, in seconds.  A
+    /// period of zero replaces it at every `pickup`.
+    pub fn set_session_period(&mut self, period: u32) {
+        self.sessions.set_period(period);
+    }
+    fn save_sessions(&self) {
+        if let Err(e) = self.sessions.write(&self.myself) {
+            info!("Unable to save sessions: {}", e);
+        }
+    }
+
+    pub fn pickup(&mut self) -> Result<(), Error> {
+        if self.sessions.rotate(format::DateRfc3339::now()) {
+            self.save_sessions();
+        }
+        try!(self.node.pickup(&self.myself));
+
+        let now = format::DateRfc3339::now();
+        for (msg_id, who, c) in self.outbox.due(now) {
+            info!("I am going to retry {}...", dht::codename(&msg_id.0));
+            try!(self.send_doubleboxed(&who, &msg_id, &c));
+            if let Err(e) = self.outbox.mark_sent(&msg_id, now) {
+                info!("Unable to update outbox: {}", e);
             }
         }
+        Ok(())
     }
 
+    /// Handle an `Acknowledge` of `msg_id` from `from`, so that we
+    /// stop resending it.  Returns the acknowledged message, unless it
+    /// was not one we were waiting for.
+    pub fn acknowledge(&mut self, from: &crypto::PublicKey, msg_id: &message::Id)
+                       -> Option<format::OutgoingMessage> {
+        if self.sessions.acknowledged(from, msg_id) {
+            info!("{} now knows our session key", from);
+        }
+        let acked = match self.outbox.acknowledge(from, msg_id) {
+            Ok(Some(m)) => {
+                info!("Acknowledgement of message {}", dht::codename(&msg_id.0));
+                Some(m)
+            },
+            Ok(None) => {
+                info!("Duplicate acknowledgement of message {}", dht::codename(&msg_id.0));
+                None
+            },
+            Err(e) => {
+                info!("Unable to update outbox: {}", e);
+                None
+            },
+        };
+        let mut q = String::new();
+        for (k, _, _) in self.outbox.unacknowledged() {
+            q = format!("{} '{}'", q, dht::codename(&k.0));
+        }
+        info!("Messages remaining in queue: {}", q);
+        acked
+    }
+    /// Like `acknowledge`, but also record in `mailbox` when `from`
+    /// received the comment that `msg_id` is part of, once every part
+    /// of that comment has been acknowledged.
+    pub fn acknowledge_delivery(&mut self, mailbox: &mut Mailbox, from: &crypto::PublicKey,
+                                msg_id: &message::Id) -> Result<(), std::io::Error> {
+        if let Some(m) = self.acknowledge(from, msg_id) {
+            if let (Some(thread), Some(comment)) = (m.thread, m.comment) {
+                if self.outbox.comment_acknowledged(&comment, from) {
+                    try!(mailbox.mark_delivered(thread, comment, from,
+                                                format::DateRfc3339::now()));
+                }
+            }
+        }
+        Ok(())
+    }
     pub fn listen(&mut self) -> Option<(crypto::PublicKey, message::Id, Message)> {
-        if let Ok(m) = self.message_receiver.try_recv() {
-            if m.destination != self.myself.public {
+        if let Some(m) = self.node.try_receive() {
+            if m.destination != self.myself.public() {
                 return None;
             }
-            if let Ok((k, msg_id, data)) = dht::double_unbox(&m.message, &self.myself.secret) {
+            if let Some((k, msg_id, data, fresh)) = self.unbox(&m.message) {
                 // println!("\r\n ****** \"{}\" ****** {}\r\n", dht::codename(&m.message),
                 //          dht::codename(&m.message[32+24 .. 32+24+6]));
                 // println!("\r\nlisten is decrypted to \"{}\" a.k.a. {:?}\r\n",
                 //          dht::codename(&data), &data[0..7]);
 
-                let m = Message::from_bytes(&data);
-                match m {
-                    Message::Acknowledge { msg_id } => {
-                        if self.unacknowledged.contains_key(&msg_id) {
-                            info!("Acknowledgement of message {}", dht::codename(&msg_id.0));
-                            self.unacknowledged.remove(&msg_id);
-                        } else {
-                            info!("Duplicate acknowledgement of message {}", dht::codename(&msg_id.0));
-                        }
-                        let mut q = String::new();
-                        for k in self.unacknowledged.keys() {
-                            q = format!("{} '{}'", q, dht::codename(&k.0));
-                        }
-                        info!("Messages remaining in queue: {}", q);
+                let m = match Message::decode(&data) {
+                    Ok((0, m)) => {
+                        // Reply in a format they can understand.
+                        self.legacy_peers.insert(k);
+                        m
+                    },
+                    Ok((_, m)) => {
+                        self.legacy_peers.remove(&k);
+                        m
+                    },
+                    Err(e) => {
+                        info!("Dropping message {} from {}: {}", dht::codename(&msg_id.0), k, e);
+                        return None;
                     },
-                    _ => {},
                 };
-
+                let now = format::rfc3339_to_epoch(format::DateRfc3339::now());
+                if self.seen.contains(&msg_id) {
+                    // This is a copy from another rendezvous, which
+                    // needs nothing more from us, or a retransmission
+                    // because our acknowledgement got lost, which we
+                    // acknowledge again.  Either way, we do not hand
+                    // it on a second time.
+                    info!("Duplicate message {} from {}", dht::codename(&msg_id.0), k);
+                    if m.needs_acknowledgement() && self.seen.acknowledge(&msg_id, now) {
+                        self.send(&k, &Message::Acknowledge { msg_id: msg_id });
+                    }
+                    return None;
+                }
+                if let Err(e) = self.seen.insert(msg_id) {
+                    info!("Unable to save seen messages: {}", e);
+                }
+                if !fresh && self.sessions.their_key(&k).is_some() {
+                    // They use sessions, but do not seem to know our
+                    // newest key.  We only ask this of new messages,
+                    // since a retransmission may well predate our key.
+                    self.sessions.mark_untold(&k);
+                }
+                // Whoever we hand it to acknowledges it now.
+                self.seen.acknowledge(&msg_id, now);
+                if let Message::Rekey { key } = m {
+                    if self.sessions.learn(&k, &key, format::DateRfc3339::now()) {
+                        info!("New session key from {}", k);
+                        self.save_sessions();
+                    }
+                    self.send(&k, &Message::Acknowledge { msg_id: msg_id });
+                    return None;
+                }
                 return Some((k, msg_id, m));
             }
         }
         None
     }
+    /// Open a message addressed to us, whether it uses our long-term
+    /// key or one of our session keys.  Also returns whether it used
+    /// our newest session key.
+    fn unbox(&mut self, c: &[u8; USER_MESSAGE_LENGTH])
+             -> Option<(crypto::PublicKey, message::Id, [u8; DECRYPTED_USER_MESSAGE_LENGTH], bool)> {
+        let inner = match dht::double_unbox_outer(c, self.myself.secret()) {
+            Ok(inner) => inner,
+            Err(_) => { return None; },
+        };
+        match self.sessions.open(&inner, &self.myself) {
+            Ok((data, fresh)) => Some((inner.from, inner.msg_id, data, fresh)),
+            Err(e) => {
+                info!("Dropping message {} from {}: {}",
+                      dht::codename(&inner.msg_id.0), inner.from, e);
+                None
+            },
+        }
+    }
+
+    /// Leave the network, once our node has sent what it had
+    /// scheduled and saved its peers.
+    pub fn shutdown(self) {
+        self.node.shutdown();
+    }
 
-    pub fn read(the_dir: &std::path::PathBuf) -> Result<AddressBook, std::io::Error> {
+    /// Read the address book from `the_dir`, joining the network as
+    /// configured by its `bootstrap` file.  Our keys are encrypted
+    /// with `passphrase`.
+    pub fn read(the_dir: &std::path::PathBuf, passphrase: &str) -> Result<AddressBook, Error> {
+        let config = try!(dht::NodeConfig::read(the_dir));
+        AddressBook::read_with_config(the_dir, &config, passphrase)
+    }
+    /// Read the address book from `the_dir`, joining the network via
+    /// the nodes in `config`.
+    pub fn read_with_config(the_dir: &std::path::PathBuf, config: &dht::NodeConfig,
+                            passphrase: &str) -> Result<AddressBook, Error> {
         let my_personal_key = {
             let mut name = the_dir.clone();
             name.push("personal.key");
-            dht::read_or_generate_keypair(name).unwrap()
+            try!(dht::read_or_generate_keypair(name, passphrase))
         };
         let (public_dir, secret_dir) = try!(AddressBook::public_secret_dirs(the_dir));
-        let (ask_rendezvous, hear_rendezvous, send, receive) = try!(dht::start_static_node(the_dir));
+        let node = try!(dht::start_static_node(the_dir, config, passphrase));
+        let sessions = Sessions::read(the_dir, &my_personal_key);
 
         let mut ab = AddressBook {
             public_ids: HashMap::new(),
             secret_ids: HashMap::new(),
-            unacknowledged: HashMap::new(),
+            outbox: try!(Outbox::read(the_dir)),
+            seen: Seen::read(the_dir),
+            legacy_peers: std::collections::HashSet::new(),
             myself: my_personal_key,
-            ask_rendezvous: ask_rendezvous,
-            hear_rendezvous: hear_rendezvous,
-            message_sender: send,
-            message_receiver: receive,
+            sessions: sessions,
+            forward_secrecy: true,
+            last_comment_time: 0,
+            node: node,
             dir: the_dir.clone(),
         };
         ab.public_ids.insert("knightley".to_string(),
                              crypto::PublicKey([140, 132, 104, 138, 2, 247, 127, 186, 197, 203, 29,
                                                 30, 17, 36, 91, 104, 91, 255, 167, 40, 118, 175, 88,
                                                 160, 79, 161, 255, 191, 215, 249, 74, 20]));
-        ab.secret_ids.insert("myself".to_string(), my_personal_key.public);
+        let me = ab.myself.public();
+        ab.secret_ids.insert("myself".to_string(), me);
+        if ab.sessions.rotate(format::DateRfc3339::now()) {
+            ab.save_sessions();
+        }
         for entry in try!(std::fs::read_dir(&secret_dir)) {
             let entry = try!(entry);
             if try!(std::fs::metadata(&entry.path())).is_file() {
@@ -595,7 +1293,7 @@ impl AddressBook {
     }
 
     pub fn my_key(&self) -> crypto::PublicKey {
-        self.myself.public
+        self.myself.public()
     }
 }
 
@@ -0,0 +1,65 @@
+//! Where the `dht` gets its randomness from when choosing routes and
+//! scheduling packets.  Normally this is a cryptographic random number
+//! generator, but a simulation can use a `SeededRng` so that it can
+//! be replayed exactly.
+
+use onionsalt::crypto;
+
+/// A source of random numbers.
+pub trait Rng: Send {
+    fn next_u32(&mut self) -> u32;
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) + self.next_u32() as u64
+    }
+}
+
+/// The cryptographic random number generator from `onionsalt`.
+#[derive(Clone, Copy, Debug)]
+pub struct OsRng;
+
+impl Rng for OsRng {
+    fn next_u32(&mut self) -> u32 {
+        crypto::random_u32()
+    }
+}
+
+/// A deterministic xorshift generator.  It is *not* suitable for
+/// anything but tests, since its output is easily predicted.
+#[derive(Clone, Copy, Debug)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        // xorshift gets stuck at zero, so we avoid it.
+        SeededRng { state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed } }
+    }
+}
+
+impl Rng for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        // This is xorshift64*.
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(2685821657736338717)
+    }
+}
+
+#[test]
+fn test_seeded_rng() {
+    let mut a = SeededRng::new(42);
+    let mut b = SeededRng::new(42);
+    let mut c = SeededRng::new(43);
+    let xa: Vec<u64> = (0 .. 100).map(|_| { a.next_u64() }).collect();
+    let xb: Vec<u64> = (0 .. 100).map(|_| { b.next_u64() }).collect();
+    let xc: Vec<u64> = (0 .. 100).map(|_| { c.next_u64() }).collect();
+    assert_eq!(xa, xb);
+    assert!(xa != xc);
+    let mut z = SeededRng::new(0);
+    assert!(z.next_u64() != 0);
+}
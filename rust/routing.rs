@@ -0,0 +1,391 @@
+//! The routing table of the `dht`, which is organized into
+//! Kademlia-style buckets by XOR distance from our own key.
+
+use std;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+use onionsalt::crypto;
+
+/// The most nodes we keep in any one bucket.
+pub const K: usize = 20;
+
+/// The number of bits in a key, and hence the number of buckets.
+const NUM_BUCKETS: usize = 256;
+
+/// The XOR distance between two keys, which compares as a 256-bit
+/// big-endian number.
+pub fn distance(a: &crypto::PublicKey, b: &crypto::PublicKey) -> [u8; 32] {
+    let mut out = [0; 32];
+    for i in 0 .. 32 {
+        out[i] = a.0[i] ^ b.0[i];
+    }
+    out
+}
+
+/// The index of the highest bit in which `a` and `b` differ, counting
+/// from the least significant bit, or `None` if they are equal.  Every
+/// node in bucket `i` is between `2^i` and `2^(i+1)` away from us.
+fn bucket_index(a: &crypto::PublicKey, b: &crypto::PublicKey) -> Option<usize> {
+    for i in 0 .. 32 {
+        let x = a.0[i] ^ b.0[i];
+        if x != 0 {
+            return Some(NUM_BUCKETS - 1 - (8*i + x.leading_zeros() as usize));
+        }
+    }
+    None
+}
+
+pub struct RoutingTable {
+    me: crypto::PublicKey,
+    /// Our own address, once we know it.
+    my_addr: Option<SocketAddr>,
+    /// Each bucket is ordered from least to most recently seen.
+    buckets: Vec<VecDeque<(crypto::PublicKey, SocketAddr)>>,
+    len: usize,
+}
+
+impl RoutingTable {
+    pub fn new(me: crypto::PublicKey) -> RoutingTable {
+        RoutingTable {
+            me: me,
+            my_addr: None,
+            buckets: (0 .. NUM_BUCKETS).map(|_| { VecDeque::new() }).collect(),
+            len: 0,
+        }
+    }
+    fn position(&self, k: &crypto::PublicKey) -> Option<(usize, usize)> {
+        bucket_index(&self.me, k).and_then(|b| {
+            self.buckets[b].iter().position(|&(kk, _)| { kk == *k }).map(|i| { (b, i) })
+        })
+    }
+    /// The number of nodes we know, including ourselves if we know
+    /// our own address.
+    pub fn len(&self) -> usize {
+        self.len + if self.my_addr.is_some() { 1 } else { 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn contains_key(&self, k: &crypto::PublicKey) -> bool {
+        self.get(k).is_some()
+    }
+    pub fn get(&self, k: &crypto::PublicKey) -> Option<&SocketAddr> {
+        if *k == self.me {
+            return self.my_addr.as_ref();
+        }
+        self.position(k).map(|(b, i)| { &self.buckets[b][i].1 })
+    }
+    /// Add or update a node, marking it as the most recently seen in
+    /// its bucket.  If the bucket was full, we evict and return the
+    /// least recently seen node that is not `live`.  If every node in
+    /// the bucket is live, we keep them all, and turn away (and
+    /// return) the newcomer instead.
+    pub fn insert(&mut self, k: crypto::PublicKey, addr: SocketAddr, live: &Liveness)
+                  -> Option<(crypto::PublicKey, SocketAddr)> {
+        let b = match bucket_index(&self.me, &k) {
+            Some(b) => b,
+            None => {
+                self.my_addr = Some(addr);
+                return None;
+            },
+        };
+        if let Some((_, i)) = self.position(&k) {
+            self.buckets[b].remove(i);
+            self.buckets[b].push_back((k, addr));
+            return None;
+        }
+        let mut evicted = None;
+        if self.buckets[b].len() >= K {
+            let i = match self.buckets[b].iter().position(|&(kk, _)| { live.get(&kk).is_none() }) {
+                Some(i) => i,
+                None => { return Some((k, addr)); },
+            };
+            evicted = self.buckets[b].remove(i);
+            self.len -= 1;
+        }
+        self.buckets[b].push_back((k, addr));
+        self.len += 1;
+        evicted
+    }
+    /// Mark `k` as just seen, so it is the last in its bucket to be
+    /// evicted.
+    pub fn touch(&mut self, k: &crypto::PublicKey) {
+        if let Some((b, i)) = self.position(k) {
+            if let Some(x) = self.buckets[b].remove(i) {
+                self.buckets[b].push_back(x);
+            }
+        }
+    }
+    pub fn remove(&mut self, k: &crypto::PublicKey) -> Option<SocketAddr> {
+        if *k == self.me {
+            return self.my_addr.take();
+        }
+        match self.position(k) {
+            Some((b, i)) => {
+                self.len -= 1;
+                self.buckets[b].remove(i).map(|(_, addr)| { addr })
+            },
+            None => None,
+        }
+    }
+    /// All the keys we know, including our own if we know our address.
+    pub fn keys(&self) -> Vec<crypto::PublicKey> {
+        let mut out = Vec::with_capacity(self.len());
+        if self.my_addr.is_some() {
+            out.push(self.me);
+        }
+        for bucket in self.buckets.iter() {
+            out.extend(bucket.iter().map(|&(k, _)| { k }));
+        }
+        out
+    }
+    /// The `i`th of the keys listed by `keys`, found without listing
+    /// them all.
+    pub fn nth(&self, i: usize) -> Option<crypto::PublicKey> {
+        let mut i = i;
+        if self.my_addr.is_some() {
+            if i == 0 {
+                return Some(self.me);
+            }
+            i -= 1;
+        }
+        for bucket in self.buckets.iter() {
+            if i < bucket.len() {
+                return Some(bucket[i].0);
+            }
+            i -= bucket.len();
+        }
+        None
+    }
+    /// The (up to) `n` nodes closest to `target`, closest first, not
+    /// counting ourselves.  We only look in as many buckets as we
+    /// need to.
+    pub fn closest(&self, target: &crypto::PublicKey, n: usize)
+                   -> Vec<(crypto::PublicKey, SocketAddr)> {
+        // Nodes in the bucket that `target` would go into are closer
+        // to it than any others.  Next come all the nodes in lower
+        // buckets, which are the same distance from `target` in the
+        // highest bit.  Finally come higher buckets, in order.
+        let mut order = Vec::with_capacity(NUM_BUCKETS);
+        match bucket_index(&self.me, target) {
+            Some(i) => {
+                order.push(vec![i]);
+                order.push((0 .. i).collect());
+                for j in i+1 .. NUM_BUCKETS {
+                    order.push(vec![j]);
+                }
+            },
+            None => {
+                for j in 0 .. NUM_BUCKETS {
+                    order.push(vec![j]);
+                }
+            },
+        }
+        let mut out = Vec::new();
+        for group in order {
+            for b in group {
+                out.extend(self.buckets[b].iter().cloned());
+            }
+            if out.len() >= n {
+                break;
+            }
+        }
+        out.sort_by(|a, b| { distance(&a.0, target).cmp(&distance(&b.0, target)) });
+        out.truncate(n);
+        out
+    }
+}
+
+impl<'a> std::ops::Index<&'a crypto::PublicKey> for RoutingTable {
+    type Output = SocketAddr;
+    fn index(&self, k: &crypto::PublicKey) -> &SocketAddr {
+        self.get(k).expect("no such key in routing table")
+    }
+}
+
+/// How much longer we expect each node to keep relaying for us.  The
+/// nodes are also kept in order of their keys, so that choosing the
+/// `i`th of them is cheap and reproducible, which iterating over a
+/// `HashMap` is not.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Liveness {
+    levels: HashMap<crypto::PublicKey, u8>,
+    /// The keys of `levels`, sorted by their bytes.
+    sorted: Vec<crypto::PublicKey>,
+}
+
+impl Liveness {
+    pub fn new() -> Liveness {
+        Liveness::default()
+    }
+    fn search(&self, k: &crypto::PublicKey) -> Result<usize, usize> {
+        self.sorted.binary_search_by(|x| { x.0.cmp(&k.0) })
+    }
+    pub fn len(&self) -> usize {
+        self.sorted.len()
+    }
+    pub fn get(&self, k: &crypto::PublicKey) -> Option<&u8> {
+        self.levels.get(k)
+    }
+    pub fn insert(&mut self, k: crypto::PublicKey, level: u8) -> Option<u8> {
+        if let Err(i) = self.search(&k) {
+            self.sorted.insert(i, k);
+        }
+        self.levels.insert(k, level)
+    }
+    pub fn remove(&mut self, k: &crypto::PublicKey) -> Option<u8> {
+        if let Ok(i) = self.search(k) {
+            self.sorted.remove(i);
+        }
+        self.levels.remove(k)
+    }
+    /// Count down the liveness of `k`, forgetting it once it reaches
+    /// zero.  Returns true if it was forgotten.
+    pub fn decrement(&mut self, k: &crypto::PublicKey) -> bool {
+        let dead = match self.levels.get_mut(k) {
+            None => false,
+            Some(level) => {
+                *level = level.saturating_sub(1);
+                *level == 0
+            },
+        };
+        if dead {
+            self.remove(k);
+        }
+        dead
+    }
+    /// The live keys, in order.
+    pub fn keys(&self) -> std::slice::Iter<crypto::PublicKey> {
+        self.sorted.iter()
+    }
+    pub fn nth(&self, i: usize) -> Option<crypto::PublicKey> {
+        self.sorted.get(i).cloned()
+    }
+}
+
+#[cfg(test)]
+fn addr(i: usize) -> SocketAddr {
+    use std::str::FromStr;
+    SocketAddr::from_str(&format!("10.0.{}.{}:54321", i / 256, i % 256)).unwrap()
+}
+
+#[test]
+fn test_distance() {
+    let a = crypto::PublicKey([0; 32]);
+    let mut b = crypto::PublicKey([0; 32]);
+    assert_eq!(bucket_index(&a, &b), None);
+    b.0[31] = 1;
+    assert_eq!(bucket_index(&a, &b), Some(0));
+    b.0[0] = 0x80;
+    assert_eq!(bucket_index(&a, &b), Some(255));
+    assert_eq!(distance(&a, &b)[0], 0x80);
+    // Keys that differ only in their last bytes are still different.
+    let mut c = crypto::PublicKey([0; 32]);
+    c.0[20] = 1;
+    assert!(distance(&a, &c) > [0; 32]);
+}
+
+#[test]
+fn test_closest() {
+    let me = crypto::box_keypair().public;
+    let mut table = RoutingTable::new(me);
+    for i in 0 .. 2000 {
+        table.insert(crypto::box_keypair().public, addr(i), &Liveness::new());
+    }
+    // The buckets far from us are full, so we cannot know everyone.
+    assert!(table.len() < 2000);
+    assert_eq!(table.len(), table.keys().len());
+    for _ in 0 .. 20 {
+        let target = crypto::box_keypair().public;
+        let mut all = table.keys();
+        all.sort_by(|a, b| { distance(a, &target).cmp(&distance(b, &target)) });
+        let closest: Vec<_> = table.closest(&target, K).iter().map(|&(k, _)| { k }).collect();
+        assert_eq!(&closest[..], &all[0 .. K]);
+    }
+    // Nodes near us are kept, so we can find ourselves.
+    let near: Vec<_> = table.closest(&me, 3).iter().map(|&(k, _)| { k }).collect();
+    let mut all = table.keys();
+    all.sort_by(|a, b| { distance(a, &me).cmp(&distance(b, &me)) });
+    assert_eq!(&near[..], &all[0 .. 3]);
+}
+
+#[test]
+fn test_eviction() {
+    let me = crypto::PublicKey([0; 32]);
+    let mut table = RoutingTable::new(me);
+    // These keys all go in the farthest bucket.
+    let far: Vec<_> = (0 .. K+2).map(|_| {
+        let mut k = crypto::box_keypair().public;
+        k.0[0] |= 0x80;
+        k
+    }).collect();
+    let mut live = Liveness::new();
+    for i in 0 .. K {
+        assert_eq!(table.insert(far[i], addr(i), &live), None);
+    }
+    assert_eq!(table.len(), K);
+    table.touch(&far[0]);
+    live.insert(far[2], 1);
+    // Since we just saw far[0], far[1] is the least recently seen, and
+    // far[2] is still relaying for us, so far[3] goes next.
+    assert_eq!(table.insert(far[K], addr(K), &live), Some((far[1], addr(1))));
+    assert_eq!(table.insert(far[K+1], addr(K+1), &live), Some((far[3], addr(3))));
+    assert_eq!(table.len(), K);
+    assert_eq!(table.get(&far[0]), Some(&addr(0)));
+    assert_eq!(table.get(&far[1]), None);
+    assert_eq!(table.get(&far[2]), Some(&addr(2)));
+    // When every node in the bucket is live, the newcomer is turned
+    // away.
+    for k in table.keys() {
+        live.insert(k, 1);
+    }
+    let mut newcomer = crypto::box_keypair().public;
+    newcomer.0[0] |= 0x80;
+    assert_eq!(table.insert(newcomer, addr(K+2), &live), Some((newcomer, addr(K+2))));
+    assert_eq!(table.get(&newcomer), None);
+    assert_eq!(table.len(), K);
+    // Our own address does not take up a place in any bucket.
+    table.insert(me, addr(1000), &live);
+    assert_eq!(table.len(), K+1);
+    assert_eq!(table[&me], addr(1000));
+    assert_eq!(table.remove(&far[0]), Some(addr(0)));
+    assert_eq!(table.len(), K);
+}
+
+#[test]
+fn test_nth() {
+    let me = crypto::box_keypair().public;
+    let mut table = RoutingTable::new(me);
+    for i in 0 .. 100 {
+        table.insert(crypto::box_keypair().public, addr(i), &Liveness::new());
+    }
+    table.insert(me, addr(1000), &Liveness::new());
+    let keys = table.keys();
+    for i in 0 .. keys.len() {
+        assert_eq!(table.nth(i), Some(keys[i]));
+    }
+    assert_eq!(table.nth(keys.len()), None);
+}
+
+#[test]
+fn test_liveness() {
+    let mut live = Liveness::new();
+    let keys: Vec<_> = (0 .. 20).map(|_| { crypto::box_keypair().public }).collect();
+    for k in keys.iter() {
+        assert_eq!(live.insert(*k, 2), None);
+    }
+    assert_eq!(live.insert(keys[0], 3), Some(2));
+    assert_eq!(live.len(), keys.len());
+    let mut sorted = keys.clone();
+    sorted.sort_by(|a, b| { a.0.cmp(&b.0) });
+    for i in 0 .. sorted.len() {
+        assert_eq!(live.nth(i), Some(sorted[i]));
+    }
+    assert!(!live.decrement(&keys[1]));
+    assert!(live.decrement(&keys[1]));
+    assert_eq!(live.get(&keys[1]), None);
+    assert_eq!(live.remove(&keys[2]), Some(2));
+    assert_eq!(live.len(), keys.len() - 2);
+    assert!(live.keys().all(|k| { *k != keys[1] && *k != keys[2] }));
+}
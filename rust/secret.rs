@@ -0,0 +1,143 @@
+//! Secret keys that are wiped from memory once we are done with them.
+//!
+//! A `crypto::KeyPair` is `Copy`, so every assignment or closure
+//! leaves another copy of the secret key lying around.  A
+//! `SecretKeyPair` cannot be copied, zeroes its secret key when it is
+//! dropped, and never shows the secret key in `Debug` output.  Share
+//! one between threads with an `Arc`.
+
+use std;
+
+use onionsalt::crypto;
+
+/// Overwrite `bytes` with zeros, in a way the compiler will not
+/// optimize away even though nobody reads them again.
+pub fn wipe(bytes: &mut [u8]) {
+    for b in bytes.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0); }
+    }
+}
+
+pub struct SecretKeyPair {
+    pair: crypto::KeyPair,
+}
+
+impl SecretKeyPair {
+    /// Take the key in `kp`, wiping the secret key out of `kp` itself.
+    pub fn new(kp: &mut crypto::KeyPair) -> SecretKeyPair {
+        let out = SecretKeyPair { pair: *kp };
+        wipe(&mut kp.secret.0);
+        out
+    }
+    /// A new random keypair.
+    pub fn generate() -> SecretKeyPair {
+        SecretKeyPair::new(&mut crypto::box_keypair())
+    }
+    /// The public key, which is no secret.
+    pub fn public(&self) -> crypto::PublicKey {
+        self.pair.public
+    }
+    /// The secret key, which is borrowed rather than copied so that
+    /// it stays where we can wipe it.
+    pub fn secret(&self) -> &crypto::SecretKey {
+        &self.pair.secret
+    }
+    /// The keypair itself, for the `onionsalt` functions that borrow
+    /// one.  For those that take it by value, use `with_copy`.
+    pub fn keypair(&self) -> &crypto::KeyPair {
+        &self.pair
+    }
+    /// Call `f` with a copy of the keypair, for the `onionsalt`
+    /// functions that take one by value, and wipe our own copy once
+    /// it returns.  The copy handed to `f` (and any that it makes in
+    /// turn) is out of our reach, and stays unwiped, so use `keypair`
+    /// wherever a borrowed keypair will do.
+    pub fn with_copy<T, F>(&self, f: F) -> T where F: FnOnce(crypto::KeyPair) -> T {
+        let mut copy = self.pair;
+        let out = f(copy);
+        wipe(&mut copy.secret.0);
+        out
+    }
+}
+
+impl Drop for SecretKeyPair {
+    fn drop(&mut self) {
+        wipe(&mut self.pair.secret.0);
+    }
+}
+
+impl std::fmt::Debug for SecretKeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SecretKeyPair {{ public: {:?}, secret: <redacted> }}", self.pair.public)
+    }
+}
+
+/// A passphrase, which is wiped from memory when dropped.  It derefs
+/// to a `str`, for the functions that borrow one.
+pub struct Passphrase(String);
+
+impl Passphrase {
+    /// Take `s`, which is wiped when the `Passphrase` is dropped.
+    pub fn new(s: String) -> Passphrase {
+        Passphrase(s)
+    }
+    /// Append a line from `stdin`.  Unless there is room enough for
+    /// it already, this moves the passphrase, leaving an unwiped copy
+    /// behind.
+    pub fn read_line(&mut self, stdin: &std::io::Stdin) -> std::io::Result<usize> {
+        stdin.read_line(&mut self.0)
+    }
+    /// Drop any line ending.
+    pub fn trim_newline(&mut self) {
+        let len = self.0.trim_right_matches(|c| { c == '\n' || c == '\r' }).len();
+        self.0.truncate(len);
+    }
+}
+
+impl std::ops::Deref for Passphrase {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        // Only the bytes that are still in use are wiped, but those
+        // that were truncated are just the line ending.
+        unsafe { wipe(self.0.as_mut_vec()); }
+    }
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Passphrase(<redacted>)")
+    }
+}
+
+#[test]
+fn test_secret_key_pair() {
+    let mut kp = crypto::box_keypair();
+    let secret = kp.secret.0;
+    let s = SecretKeyPair::new(&mut kp);
+    assert_eq!(kp.secret.0, [0; 32]);
+    assert_eq!(kp.public, s.public());
+    assert_eq!(s.secret().0, secret);
+
+    assert_eq!(s.with_copy(|kp| { kp.secret.0 }), secret);
+
+    let shown = format!("{:?}", s);
+    assert!(shown.contains("redacted"));
+    assert!(!shown.contains(&format!("{:?}", secret)));
+
+    let mut bytes = [1; 40];
+    wipe(&mut bytes[3 .. 33]);
+    assert_eq!(&bytes[3 .. 33], &[0; 30][..]);
+    assert_eq!(bytes[2], 1);
+    assert_eq!(bytes[33], 1);
+
+    let mut p = Passphrase::new("correct horse\r\n".to_string());
+    p.trim_newline();
+    assert_eq!(&*p, "correct horse");
+    assert_eq!(format!("{:?}", p), "Passphrase(<redacted>)");
+}
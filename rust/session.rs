@@ -0,0 +1,507 @@
+//! Forward-secret sessions between contacts.
+//!
+//! A message `double_box`ed between our long-term keys can be read by
+//! anyone who records it and later steals the recipient's secret key.
+//! So we also keep a session keypair, which we replace every
+//! `SESSION_PERIOD` and tell our contacts about in a `Rekey` message.
+//! The `Rekey` itself is sealed with long-term keys, since the
+//! contact cannot yet open anything sealed with the key it announces.
+//! Once we know a contact's session key, and they have acknowledged a
+//! `Rekey` telling them ours, the inner box of each message between
+//! us is made with session keys, and since we wipe
+//! our session keys a while after replacing them, a stolen key only
+//! exposes the messages of the last few days.
+//!
+//! Contacts whose clients predate sessions never send a `Rekey`, so we
+//! keep using our long-term keys with them.
+
+use std;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use onionsalt::crypto;
+use serde_json;
+
+use dht;
+use error::Error;
+use message;
+use format;
+use format::{OurSessionKey, SessionState, TheirSessionKey};
+use secret::{wipe, SecretKeyPair};
+
+/// How often we replace our session key, in seconds.
+pub const SESSION_PERIOD: u32 = 24*60*60;
+
+/// How long we keep a session key after it has been replaced, in
+/// seconds.  This allows for a message to be retried for as long as
+/// the default `RetryPolicy` allows, and then to wait at a rendezvous.
+pub const SESSION_LIFETIME: u32 = 10*24*60*60;
+
+struct OurKey {
+    key: SecretKeyPair,
+    created: u32,
+}
+
+struct TheirKey {
+    key: crypto::PublicKey,
+    learned: u32,
+}
+
+pub struct Sessions {
+    name: std::path::PathBuf,
+    period: u32,
+    /// Our session keys, newest first.
+    ours: Vec<OurKey>,
+    /// The session keys each contact has told us about, newest first.
+    theirs: HashMap<crypto::PublicKey, Vec<TheirKey>>,
+    /// The newest of our session keys each contact has acknowledged.
+    told: HashMap<crypto::PublicKey, crypto::PublicKey>,
+    /// The `Rekey` messages telling contacts one of our session keys,
+    /// which they have yet to acknowledge.
+    telling: HashMap<crypto::PublicKey, (message::Id, crypto::PublicKey)>,
+    /// The pair of keys, ours and theirs, with which each contact's
+    /// last message was opened, which we try first next time.
+    last_used: HashMap<crypto::PublicKey, (crypto::PublicKey, crypto::PublicKey)>,
+}
+
+/// The index of the first key that was replaced at least
+/// `SESSION_LIFETIME` before `now`, given the times at which each key
+/// (newest first) was made.  That key and all older ones can go.
+fn first_expired<I: Iterator<Item=u32>>(times: I, now: u32) -> Option<usize> {
+    let times: Vec<u32> = times.collect();
+    (1 .. times.len()).find(|&i| { now.saturating_sub(times[i-1]) >= SESSION_LIFETIME })
+}
+
+impl Sessions {
+    /// Read our sessions from the "sessions" file in `the_dir`, which
+    /// is boxed to ourselves as `myself`.  If there is no such file,
+    /// or we cannot read it, we start afresh.
+    pub fn read(the_dir: &std::path::PathBuf, myself: &SecretKeyPair) -> Sessions {
+        let mut name = the_dir.clone();
+        name.push("sessions");
+        let mut sessions = Sessions {
+            name: name,
+            period: SESSION_PERIOD,
+            ours: Vec::new(),
+            theirs: HashMap::new(),
+            told: HashMap::new(),
+            telling: HashMap::new(),
+            last_used: HashMap::new(),
+        };
+        match read_state(&sessions.name, myself) {
+            Ok(state) => sessions.load(state),
+            Err(e) => info!("Starting new sessions: {}", e),
+        }
+        sessions
+    }
+    fn load(&mut self, state: SessionState) {
+        for mut k in state.ours {
+            if k.secret.len() == 32 {
+                let mut kp = crypto::KeyPair {
+                    public: k.public,
+                    secret: crypto::SecretKey(*array_ref![k.secret, 0, 32]),
+                };
+                self.ours.push(OurKey {
+                    key: SecretKeyPair::new(&mut kp),
+                    created: format::rfc3339_to_epoch(k.created),
+                });
+            }
+            wipe(&mut k.secret);
+        }
+        for k in state.theirs {
+            self.theirs.entry(k.contact).or_insert(Vec::new()).push(TheirKey {
+                key: k.key,
+                learned: format::rfc3339_to_epoch(k.learned),
+            });
+        }
+    }
+    /// Save our sessions, boxed to ourselves as `myself`.
+    pub fn write(&self, myself: &SecretKeyPair) -> Result<(), Error> {
+        let mut state = SessionState {
+            ours: self.ours.iter().map(|k| {
+                OurSessionKey {
+                    public: k.key.public(),
+                    secret: k.key.secret().0.to_vec(),
+                    created: format::epoch_to_rfc3339(k.created),
+                }
+            }).collect(),
+            theirs: Vec::new(),
+        };
+        for (contact, keys) in self.theirs.iter() {
+            for k in keys.iter() {
+                state.theirs.push(TheirSessionKey {
+                    contact: *contact,
+                    key: k.key,
+                    learned: format::epoch_to_rfc3339(k.learned),
+                });
+            }
+        }
+        let result = write_state(&self.name, &state, myself);
+        for k in state.ours.iter_mut() {
+            wipe(&mut k.secret);
+        }
+        result
+    }
+    /// Change how often we replace our session key.  A period of zero
+    /// replaces it every time we `rotate`.
+    pub fn set_period(&mut self, period: u32) {
+        self.period = period;
+    }
+    /// Replace our session key if it is older than our period, and
+    /// forget the keys (ours and theirs) that were replaced too long
+    /// ago to be of any use.  Returns whether anything changed, and so
+    /// needs saving.
+    pub fn rotate(&mut self, now: format::DateRfc3339) -> bool {
+        let now = format::rfc3339_to_epoch(now);
+        let mut changed = false;
+        let due = match self.ours.first() {
+            Some(k) => now.saturating_sub(k.created) >= self.period,
+            None => true,
+        };
+        if due {
+            self.ours.insert(0, OurKey { key: SecretKeyPair::generate(), created: now });
+            changed = true;
+        }
+        let expired = first_expired(self.ours.iter().map(|k| { k.created }), now);
+        if let Some(i) = expired {
+            // Dropping the keys wipes them.
+            self.ours.truncate(i);
+            changed = true;
+        }
+        for keys in self.theirs.values_mut() {
+            let expired = first_expired(keys.iter().map(|k| { k.learned }), now);
+            if let Some(i) = expired {
+                keys.truncate(i);
+                changed = true;
+            }
+        }
+        changed
+    }
+    /// Our newest session key.
+    pub fn current(&self) -> Option<&SecretKeyPair> {
+        self.ours.first().map(|k| { &k.key })
+    }
+    /// Our newest session key, if `contact` has not yet acknowledged
+    /// it, and is not waiting to.
+    pub fn untold(&self, contact: &crypto::PublicKey) -> Option<crypto::PublicKey> {
+        let current = match self.current() {
+            Some(k) => k.public(),
+            None => { return None; },
+        };
+        if self.told.get(contact) == Some(&current)
+            || self.telling.get(contact).map(|t| { t.1 }) == Some(current) {
+            return None;
+        }
+        Some(current)
+    }
+    /// The newest of our session keys that `contact` has acknowledged,
+    /// if we still have it.
+    pub fn told_key(&self, contact: &crypto::PublicKey) -> Option<&SecretKeyPair> {
+        let key = match self.told.get(contact) {
+            Some(k) => *k,
+            None => { return None; },
+        };
+        self.ours.iter().find(|k| { k.key.public() == key }).map(|k| { &k.key })
+    }
+    /// Note that `msg_id` is a `Rekey` telling `contact` our newest
+    /// session key.  They count as told once they acknowledge it.
+    pub fn telling(&mut self, contact: &crypto::PublicKey, msg_id: message::Id) {
+        if let Some(k) = self.current().map(|k| { k.public() }) {
+            self.telling.insert(*contact, (msg_id, k));
+        }
+    }
+    /// Note that `contact` acknowledged `msg_id`.  Returns true if it
+    /// was a `Rekey` telling them one of our session keys.
+    pub fn acknowledged(&mut self, contact: &crypto::PublicKey, msg_id: &message::Id) -> bool {
+        let key = match self.telling.get(contact) {
+            Some(&(id, key)) if id == *msg_id => key,
+            _ => { return false; },
+        };
+        self.telling.remove(contact);
+        self.told.insert(*contact, key);
+        true
+    }
+    /// Note that `contact` seems not to know our session key after
+    /// all, so that we tell them again, and until they acknowledge it
+    /// use long-term keys with them.
+    pub fn mark_untold(&mut self, contact: &crypto::PublicKey) {
+        self.told.remove(contact);
+    }
+    /// Note that `contact` told us their session key is now `key`.
+    /// Returns whether this is news to us.
+    pub fn learn(&mut self, contact: &crypto::PublicKey, key: &crypto::PublicKey,
+                 now: format::DateRfc3339) -> bool {
+        let keys = self.theirs.entry(*contact).or_insert(Vec::new());
+        if keys.first().map(|k| { k.key }) == Some(*key) {
+            return false;
+        }
+        let old: Vec<usize> = (0 .. keys.len()).filter(|&i| { keys[i].key == *key }).collect();
+        for i in old.into_iter().rev() {
+            keys.remove(i);
+        }
+        keys.insert(0, TheirKey { key: *key, learned: format::rfc3339_to_epoch(now) });
+        true
+    }
+    /// The newest session key of `contact`, if they have told us one.
+    pub fn their_key(&self, contact: &crypto::PublicKey) -> Option<crypto::PublicKey> {
+        self.theirs.get(contact).and_then(|keys| { keys.first() }).map(|k| { k.key })
+    }
+    /// Seal `plaintext` from `myself` to `contact`.  The inner box
+    /// uses their newest session key and the newest of ours that they
+    /// have acknowledged, when `use_sessions` and we have both, and
+    /// otherwise our long-term keys.
+    pub fn seal(&self, plaintext: &[u8; dht::DECRYPTED_USER_MESSAGE_LENGTH],
+                contact: &crypto::PublicKey, myself: &SecretKeyPair, use_sessions: bool)
+                -> (message::Id, [u8; dht::USER_MESSAGE_LENGTH]) {
+        let theirs = if use_sessions { self.their_key(contact) } else { None };
+        match (theirs, self.told_key(contact)) {
+            (Some(theirs), Some(ours)) => {
+                dht::double_box_with(plaintext, contact, &theirs, &myself.public(), ours.secret())
+            },
+            _ => dht::double_box(plaintext, contact, myself.keypair()),
+        }
+    }
+    /// Open the inner box of a message from `inner.from`, which is
+    /// made either with our long-term keys, or with one of their
+    /// session keys and one of ours.  Also returns whether they used
+    /// our newest session key, and hence know it.  We start with the
+    /// keys that opened their last message, which are nearly always
+    /// the right ones, so that we seldom try every pair.
+    pub fn open(&mut self, inner: &dht::InnerBox, myself: &SecretKeyPair)
+                -> Result<([u8; dht::DECRYPTED_USER_MESSAGE_LENGTH], bool), Error> {
+        if let Some(&(our_key, their_key)) = self.last_used.get(&inner.from) {
+            if let Some(i) = self.ours.iter().position(|k| { k.key.public() == our_key }) {
+                if let Ok(p) = inner.open(&their_key, self.ours[i].key.secret()) {
+                    return Ok((p, i == 0));
+                }
+            }
+        }
+        if let Ok(p) = inner.open(&inner.from, myself.secret()) {
+            return Ok((p, false));
+        }
+        let mut opened = None;
+        if let Some(theirs) = self.theirs.get(&inner.from) {
+            'search: for (i, ours) in self.ours.iter().enumerate() {
+                for t in theirs.iter() {
+                    if let Ok(p) = inner.open(&t.key, ours.key.secret()) {
+                        opened = Some((p, i == 0, ours.key.public(), t.key));
+                        break 'search;
+                    }
+                }
+            }
+        }
+        match opened {
+            Some((p, fresh, our_key, their_key)) => {
+                self.last_used.insert(inner.from, (our_key, their_key));
+                Ok((p, fresh))
+            },
+            None => Err(Error::Crypto),
+        }
+    }
+}
+
+/// Write `state` to `name`, boxed from and to `myself`, as a nonce
+/// followed by the box.
+fn write_state(name: &std::path::Path, state: &SessionState, myself: &SecretKeyPair)
+               -> Result<(), Error> {
+    let mut plain = vec![0; 32];
+    if let Err(e) = serde_json::to_writer(&mut plain, state) {
+        wipe(&mut plain);
+        return Err(Error::Storage(std::io::Error::new(std::io::ErrorKind::Other,
+                                                      format!("error writing json {}", e))));
+    }
+    let n = crypto::random_nonce();
+    let mut sealed = vec![0; plain.len()];
+    crypto::box_up(&mut sealed, &plain, &n, &myself.public(), myself.secret());
+    wipe(&mut plain);
+    // As with key files, we never leave a half-written file behind.
+    let tmp = name.with_extension("tmp");
+    {
+        let mut f = try!(std::fs::File::create(&tmp));
+        try!(f.write_all(&n.0));
+        try!(f.write_all(&sealed[16..]));
+        try!(f.sync_all());
+    }
+    try!(std::fs::rename(&tmp, name));
+    Ok(())
+}
+
+fn read_state(name: &std::path::Path, myself: &SecretKeyPair) -> Result<SessionState, Error> {
+    let mut f = try!(std::fs::File::open(name));
+    let mut data = Vec::new();
+    try!(f.read_to_end(&mut data));
+    if data.len() < 24 + 16 {
+        return Err(Error::MalformedPacket);
+    }
+    let n = crypto::Nonce(*array_ref![data, 0, 24]);
+    let mut sealed = vec![0; 16];
+    sealed.extend(data[24..].iter().cloned());
+    let mut plain = vec![0; sealed.len()];
+    try!(crypto::box_open(&mut plain, &sealed, &n, &myself.public(), myself.secret()));
+    let state = serde_json::from_reader(&plain[32..]);
+    wipe(&mut plain);
+    match state {
+        Ok(s) => Ok(s),
+        Err(e) => Err(Error::Storage(std::io::Error::new(std::io::ErrorKind::Other,
+                                                         format!("error reading json {}", e)))),
+    }
+}
+
+#[test]
+fn test_rotate() {
+    let dir = format::test_dir();
+    let me = SecretKeyPair::generate();
+    let you = crypto::box_keypair().public;
+    let mut s = Sessions::read(&dir, &me);
+    let t = |secs: u32| { format::epoch_to_rfc3339(1000*1000 + secs) };
+    assert!(s.current().is_none());
+    assert!(s.rotate(t(0)));
+    let first = s.current().unwrap().public();
+    assert_eq!(s.untold(&you), Some(first));
+    // Once we have sent a Rekey, we wait for its acknowledgement.
+    let rekey = message::Id::random();
+    s.telling(&you, rekey);
+    assert_eq!(s.untold(&you), None);
+    assert!(!s.acknowledged(&you, &message::Id::random()));
+    assert!(!s.acknowledged(&crypto::box_keypair().public, &rekey));
+    assert!(s.acknowledged(&you, &rekey));
+    assert_eq!(s.untold(&you), None);
+    assert_eq!(s.told_key(&you).unwrap().public(), first);
+    s.mark_untold(&you);
+    assert_eq!(s.untold(&you), Some(first));
+    assert!(s.told_key(&you).is_none());
+    s.telling(&you, rekey);
+    assert!(s.acknowledged(&you, &rekey));
+    assert!(!s.rotate(t(SESSION_PERIOD - 1)));
+
+    // A new key must be told to everyone again, but the old one is
+    // kept for a while, and used until they acknowledge the new one.
+    assert!(s.rotate(t(SESSION_PERIOD)));
+    let second = s.current().unwrap().public();
+    assert!(second != first);
+    assert_eq!(s.untold(&you), Some(second));
+    assert_eq!(s.told_key(&you).unwrap().public(), first);
+    assert_eq!(s.ours.len(), 2);
+    s.set_period(2*SESSION_LIFETIME);
+    assert!(!s.rotate(t(SESSION_PERIOD + SESSION_LIFETIME - 1)));
+    assert!(s.rotate(t(SESSION_PERIOD + SESSION_LIFETIME)));
+    assert_eq!(s.ours.len(), 1);
+    assert_eq!(s.current().unwrap().public(), second);
+    assert!(s.told_key(&you).is_none());
+
+    assert!(s.learn(&you, &first, t(0)));
+    assert!(!s.learn(&you, &first, t(1)));
+    assert!(s.learn(&you, &second, t(2)));
+    assert_eq!(s.their_key(&you), Some(second));
+    s.write(&me).unwrap();
+
+    let s2 = Sessions::read(&dir, &me);
+    assert_eq!(s2.current().unwrap().public(), second);
+    assert_eq!(s2.current().unwrap().secret().0, s.current().unwrap().secret().0);
+    assert_eq!(s2.their_key(&you), Some(second));
+    assert_eq!(s2.theirs[&you].len(), 2);
+    // Nobody else can read our sessions.
+    let s3 = Sessions::read(&dir, &SecretKeyPair::generate());
+    assert!(s3.current().is_none());
+}
+
+#[test]
+fn test_session_open() {
+    let dir = format::test_dir();
+    let alice = SecretKeyPair::generate();
+    let bob = SecretKeyPair::generate();
+    let now = format::DateRfc3339::now();
+    let mut a = Sessions::read(&dir.join("alice"), &alice);
+    let mut b = Sessions::read(&dir.join("bob"), &bob);
+    a.rotate(now);
+    b.rotate(now);
+    a.learn(&bob.public(), &b.current().unwrap().public(), now);
+    b.learn(&alice.public(), &a.current().unwrap().public(), now);
+
+    let mut p = [0; dht::DECRYPTED_USER_MESSAGE_LENGTH];
+    p[7] = 7;
+    let (_, c) = dht::double_box_with(&p, &bob.public(), &a.their_key(&bob.public()).unwrap(),
+                                      &alice.public(), a.current().unwrap().secret());
+    let inner = dht::double_unbox_outer(&c, bob.secret()).unwrap();
+    assert_eq!(inner.from, alice.public());
+    let (q, fresh) = b.open(&inner, &bob).unwrap();
+    assert_eq!(q[7], 7);
+    assert!(fresh);
+    assert_eq!(b.last_used[&alice.public()],
+               (b.current().unwrap().public(), a.current().unwrap().public()));
+
+    // Once Bob replaces his session key, he can still read the
+    // message, but Alice is due to learn his new key.
+    b.set_period(0);
+    b.rotate(now);
+    let (q, fresh) = b.open(&inner, &bob).unwrap();
+    assert_eq!(q[7], 7);
+    assert!(!fresh);
+    // Messages between long-term keys still work.
+    let (_, c) = dht::double_box(&p, &bob.public(), alice.keypair());
+    let inner = dht::double_unbox_outer(&c, bob.secret()).unwrap();
+    assert_eq!(b.open(&inner, &bob).unwrap().0[7], 7);
+    // But someone who stole Bob's long-term key cannot read a session
+    // message.
+    let (_, c) = dht::double_box_with(&p, &bob.public(), &b.current().unwrap().public(),
+                                      &alice.public(), a.current().unwrap().secret());
+    let inner = dht::double_unbox_outer(&c, bob.secret()).unwrap();
+    assert!(inner.open(&alice.public(), bob.secret()).is_err());
+    assert!(Sessions::read(&dir.join("eve"), &bob).open(&inner, &bob).is_err());
+}
+
+#[test]
+fn test_session_exchange() {
+    let dir = format::test_dir();
+    let alice = SecretKeyPair::generate();
+    let bob = SecretKeyPair::generate();
+    let now = format::DateRfc3339::now();
+    let mut a = Sessions::read(&dir.join("alice"), &alice);
+    let mut b = Sessions::read(&dir.join("bob"), &bob);
+    a.rotate(now);
+    b.rotate(now);
+    // Send a message as AddressBook::send does, telling the recipient
+    // our session key first, and have them open it as listen does,
+    // acknowledging any Rekey.
+    fn send(from: &mut Sessions, from_key: &SecretKeyPair, to: &mut Sessions,
+            to_key: &SecretKeyPair, x: u8) -> bool {
+        if let Some(key) = from.untold(&to_key.public()) {
+            let mut p = [0; dht::DECRYPTED_USER_MESSAGE_LENGTH];
+            *array_mut_ref![p, 0, 32] = key.0;
+            let (id, c) = from.seal(&p, &to_key.public(), from_key, false);
+            from.telling(&to_key.public(), id);
+            let inner = dht::double_unbox_outer(&c, to_key.secret()).unwrap();
+            let (q, _) = to.open(&inner, to_key).unwrap();
+            to.learn(&from_key.public(), &crypto::PublicKey(*array_ref![q, 0, 32]),
+                     format::DateRfc3339::now());
+            assert!(from.acknowledged(&to_key.public(), &id));
+        }
+        let mut p = [0; dht::DECRYPTED_USER_MESSAGE_LENGTH];
+        p[7] = x;
+        let (_, c) = from.seal(&p, &to_key.public(), from_key, true);
+        let inner = dht::double_unbox_outer(&c, to_key.secret()).unwrap();
+        // Nobody with only the long-term key can read it, once sessions
+        // are under way.
+        let secret = inner.open(&from_key.public(), to_key.secret()).is_err();
+        let (q, _) = to.open(&inner, to_key).unwrap();
+        assert_eq!(q[7], x);
+        secret
+    }
+    // Alice does not yet know Bob's key, so her first message is not
+    // secret, but his reply is.
+    assert!(!send(&mut a, &alice, &mut b, &bob, 1));
+    assert!(send(&mut b, &bob, &mut a, &alice, 2));
+    assert!(send(&mut a, &alice, &mut b, &bob, 3));
+    // After either of them replaces their key, everything still gets
+    // through.
+    a.set_period(0);
+    a.rotate(now);
+    assert!(send(&mut a, &alice, &mut b, &bob, 4));
+    assert!(send(&mut b, &bob, &mut a, &alice, 5));
+    b.set_period(0);
+    b.rotate(now);
+    assert!(send(&mut b, &bob, &mut a, &alice, 6));
+    assert!(send(&mut a, &alice, &mut b, &bob, 7));
+    assert_eq!(a.told_key(&bob.public()).unwrap().public(), a.current().unwrap().public());
+    assert_eq!(b.their_key(&alice.public()), Some(a.current().unwrap().public()));
+}
@@ -0,0 +1,488 @@
+//! A simulated network that lives entirely within one process, so
+//! that many nodes can be tested together without opening any
+//! sockets.  Packets can be dropped, delayed and reordered at random,
+//! as chosen by a seeded generator, so that a run can be repeated.
+
+use std;
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::Ordering;
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, channel, sync_channel};
+use std::time::Duration;
+
+use udp;
+use udp::RawEncryptedMessage;
+use transport::Transport;
+use clock::{Clock, SystemClock};
+use error::Error;
+use rng::{Rng, SeededRng};
+#[cfg(test)]
+use clock::FakeClock;
+
+/// The longest the simulated network waits before checking whether it
+/// is still in use, or whether its clock has been moved on.
+const SIM_IDLE_MS: u64 = 10;
+
+/// How badly the simulated network behaves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimConfig {
+    /// The probability that any given packet is lost.
+    pub loss: f64,
+    pub min_latency_ms: u64,
+    pub max_latency_ms: u64,
+    /// The probability that a packet is held back for an extra
+    /// `max_latency_ms`, so that it arrives after packets sent later.
+    pub reorder: f64,
+    /// The seed from which losses, latencies and reorderings are
+    /// drawn.
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            loss: 0.0,
+            min_latency_ms: 0,
+            max_latency_ms: 0,
+            reorder: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Counts of what has happened to the packets on a `SimNetwork`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: u64,
+    pub delivered: u64,
+    /// Packets that were lost, or sent to an address with no node.
+    pub dropped: u64,
+}
+
+struct InFlight {
+    deliver_at: u64,
+    seq: u64,
+    to: SocketAddr,
+    msg: RawEncryptedMessage,
+}
+// We order packets so that `BinaryHeap` gives us the earliest first.
+impl Ord for InFlight {
+    fn cmp(&self, other: &InFlight) -> Ordering {
+        (other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+    }
+}
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &InFlight) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for InFlight {
+    fn eq(&self, other: &InFlight) -> bool {
+        self.deliver_at == other.deliver_at && self.seq == other.seq
+    }
+}
+impl Eq for InFlight {}
+
+struct SimState {
+    config: SimConfig,
+    clock: Arc<Clock>,
+    nodes: HashMap<SocketAddr, Sender<RawEncryptedMessage>>,
+    in_flight: BinaryHeap<InFlight>,
+    seq: u64,
+    num_nodes: u32,
+    stats: SimStats,
+    rng: SeededRng,
+}
+
+impl SimState {
+    fn chance(&mut self, p: f64) -> bool {
+        (self.rng.next_u32() as f64) < p * 4294967296.0
+    }
+}
+
+/// A simulated network, which may be cloned to share it.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+    /// Wakes the delivery thread when a packet is sent.
+    wake: Arc<Condvar>,
+}
+
+impl SimNetwork {
+    pub fn new(config: SimConfig) -> SimNetwork {
+        SimNetwork::with_clock(config, Arc::new(SystemClock))
+    }
+    /// A network whose latencies are measured by `clock`.
+    pub fn with_clock(config: SimConfig, clock: Arc<Clock>) -> SimNetwork {
+        let state = Arc::new(Mutex::new(SimState {
+            config: config,
+            clock: clock,
+            nodes: HashMap::new(),
+            in_flight: BinaryHeap::new(),
+            seq: 0,
+            num_nodes: 0,
+            stats: SimStats::default(),
+            rng: SeededRng::new(config.seed),
+        }));
+        let weak = Arc::downgrade(&state);
+        let wake = Arc::new(Condvar::new());
+        let waker = wake.clone();
+        std::thread::spawn(move|| {
+            // This thread delivers packets when they are due, sleeping
+            // until the next one is (or until another is sent), and
+            // quits once the network itself is gone.
+            loop {
+                let state = match weak.upgrade() {
+                    Some(s) => s,
+                    None => { return; },
+                };
+                let mut state = state.lock().unwrap();
+                let now = state.clock.now_ms();
+                while state.in_flight.peek().map(|p| { p.deliver_at <= now }).unwrap_or(false) {
+                    let p = state.in_flight.pop().unwrap();
+                    let ok = match state.nodes.get(&p.to) {
+                        Some(node) => node.send(p.msg).is_ok(),
+                        None => false,
+                    };
+                    if ok {
+                        state.stats.delivered += 1;
+                    } else {
+                        state.nodes.remove(&p.to);
+                        state.stats.dropped += 1;
+                    }
+                }
+                let wait = match state.in_flight.peek() {
+                    Some(p) => std::cmp::min(p.deliver_at - now, SIM_IDLE_MS),
+                    None => SIM_IDLE_MS,
+                };
+                let _ = waker.wait_timeout(state, Duration::from_millis(wait)).unwrap();
+            }
+        });
+        SimNetwork { state: state, wake: wake }
+    }
+    /// A `Transport` for a new node on this network, with an address
+    /// of its own.
+    pub fn add_node(&self) -> SimTransport {
+        let mut state = self.state.lock().unwrap();
+        state.num_nodes += 1;
+        let n = state.num_nodes;
+        let ip = Ipv4Addr::new(10, (n >> 16) as u8, (n >> 8) as u8, n as u8);
+        SimTransport {
+            network: self.clone(),
+            addr: SocketAddr::V4(SocketAddrV4::new(ip, udp::PORT)),
+        }
+    }
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().unwrap().stats
+    }
+    fn send(&self, from: SocketAddr, msg: RawEncryptedMessage) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.sent += 1;
+        let config = state.config;
+        if state.chance(config.loss) {
+            state.stats.dropped += 1;
+            return;
+        }
+        let mut latency = config.min_latency_ms;
+        if config.max_latency_ms > config.min_latency_ms {
+            latency += state.rng.next_u64() % (config.max_latency_ms - config.min_latency_ms + 1);
+        }
+        if state.chance(config.reorder) {
+            latency += config.max_latency_ms + 1;
+        }
+        state.seq += 1;
+        let seq = state.seq;
+        state.in_flight.push(InFlight {
+            deliver_at: state.clock.now_ms() + latency,
+            seq: seq,
+            to: msg.ip,
+            msg: RawEncryptedMessage { ip: from, data: msg.data },
+        });
+        self.wake.notify_one();
+    }
+}
+
+/// One node's connection to a `SimNetwork`.
+pub struct SimTransport {
+    network: SimNetwork,
+    addr: SocketAddr,
+}
+
+impl SimTransport {
+    /// The address at which other nodes can reach this one.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for SimTransport {
+    fn open(self, _send_period_ms: u64, _stop: Arc<AtomicBool>)
+            -> Result<(SyncSender<RawEncryptedMessage>,
+                       Receiver<RawEncryptedMessage>,
+                       Vec<std::thread::JoinHandle<()>>), Error> {
+        let (ts, rs): (SyncSender<RawEncryptedMessage>,
+                       Receiver<RawEncryptedMessage>) = sync_channel(0);
+        let (tr, rr) = channel();
+        self.network.state.lock().unwrap().nodes.insert(self.addr, tr);
+        let network = self.network;
+        let addr = self.addr;
+        let t = std::thread::spawn(move|| {
+            for m in rs.iter() {
+                network.send(addr, m);
+            }
+            // Once the node stops sending, it leaves the network, which
+            // closes its `Receiver`.
+            network.state.lock().unwrap().nodes.remove(&addr);
+        });
+        Ok((ts, rr, vec![t]))
+    }
+}
+
+#[cfg(test)]
+fn packet(to: SocketAddr, n: u8) -> RawEncryptedMessage {
+    RawEncryptedMessage { ip: to, data: [n; udp::PACKET_LENGTH] }
+}
+
+#[test]
+fn test_simnet_delivery() {
+    let net = SimNetwork::new(SimConfig::default());
+    let a = net.add_node();
+    let b = net.add_node();
+    let (a_addr, b_addr) = (a.addr(), b.addr());
+    assert!(a_addr != b_addr);
+    let (send_a, _get_a, _) = a.open(10, Arc::new(AtomicBool::new(false))).unwrap();
+    let (_send_b, get_b, _) = b.open(10, Arc::new(AtomicBool::new(false))).unwrap();
+    send_a.send(packet(b_addr, 7)).unwrap();
+    let p = get_b.recv().unwrap();
+    assert_eq!(p.ip, a_addr);
+    assert_eq!(p.data[0], 7);
+    assert_eq!(net.stats().delivered, 1);
+}
+
+#[test]
+fn test_simnet_loss() {
+    let net = SimNetwork::new(SimConfig { loss: 1.0, .. SimConfig::default() });
+    let a = net.add_node();
+    let b = net.add_node();
+    let b_addr = b.addr();
+    let (send_a, _get_a, _) = a.open(10, Arc::new(AtomicBool::new(false))).unwrap();
+    let (_send_b, get_b, _) = b.open(10, Arc::new(AtomicBool::new(false))).unwrap();
+    for i in 0 .. 10 {
+        send_a.send(packet(b_addr, i)).unwrap();
+    }
+    std::thread::sleep_ms(50);
+    assert!(get_b.try_recv().is_err());
+    assert_eq!(net.stats(), SimStats { sent: 10, delivered: 0, dropped: 10 });
+}
+
+#[test]
+fn test_simnet_reordering() {
+    let net = SimNetwork::new(SimConfig {
+        min_latency_ms: 1,
+        max_latency_ms: 20,
+        reorder: 0.5,
+        .. SimConfig::default()
+    });
+    let a = net.add_node();
+    let b = net.add_node();
+    let b_addr = b.addr();
+    let (send_a, _get_a, _) = a.open(10, Arc::new(AtomicBool::new(false))).unwrap();
+    let (_send_b, get_b, _) = b.open(10, Arc::new(AtomicBool::new(false))).unwrap();
+    let num = 100;
+    for i in 0 .. num {
+        send_a.send(packet(b_addr, i)).unwrap();
+    }
+    let mut got: Vec<u8> = (0 .. num).map(|_| { get_b.recv().unwrap().data[0] }).collect();
+    let in_order = got.windows(2).all(|w| { w[0] < w[1] });
+    got.sort();
+    assert_eq!(got, (0 .. num).collect::<Vec<u8>>());
+    // The odds of 100 packets arriving in order are astronomical.
+    assert!(!in_order);
+}
+
+#[test]
+fn test_simnet_gift_exchange() {
+    use dht;
+    use rng;
+    use secret::SecretKeyPair;
+    let net = SimNetwork::new(SimConfig {
+        loss: 0.05,
+        min_latency_ms: 1,
+        max_latency_ms: 10,
+        .. SimConfig::default()
+    });
+    let num = 20;
+    let keys: Vec<_> = (0 .. num).map(|_| { SecretKeyPair::generate() }).collect();
+    let publics: Vec<_> = keys.iter().map(|k| { k.public() }).collect();
+    let transports: Vec<_> = (0 .. num).map(|_| { net.add_node() }).collect();
+    let first = dht::RoutingGift { addr: transports[0].addr(), key: publics[0] };
+    let mut nodes = Vec::new();
+    for ((i, t), k) in transports.into_iter().enumerate().zip(keys) {
+        let config = dht::NodeConfig {
+            bootstrap: if i == 0 { Vec::new() } else { vec![first] },
+            use_default_bootstrap: false,
+            send_period_ms: 50,
+            .. dht::NodeConfig::default()
+        };
+        nodes.push(dht::start_node(k, &config, t, Arc::new(SystemClock),
+                                   Box::new(rng::SeededRng::new(i as u64))).unwrap());
+    }
+    // Node 1 only knows about node 0 to begin with, so it can only
+    // learn of node 2 by exchanging gifts.
+    let deadline = udp::now_ms() + 60*1000;
+    loop {
+        if nodes[1].rendezvous(&publics[2]).unwrap().contains(&publics[2]) {
+            break;
+        }
+        assert!(udp::now_ms() < deadline, "node 1 never heard of node 2");
+        std::thread::sleep_ms(100);
+    }
+    assert!(net.stats().delivered > 0);
+    let status = nodes[1].status().unwrap();
+    assert!(status.known_peers >= 2);
+}
+
+#[test]
+fn test_node_shutdown() {
+    use dht;
+    use format;
+    use rng;
+    use secret::SecretKeyPair;
+    let net = SimNetwork::new(SimConfig::default());
+    let dir = format::test_dir();
+    let keys: Vec<_> = (0 .. 2).map(|_| { SecretKeyPair::generate() }).collect();
+    let publics: Vec<_> = keys.iter().map(|k| { k.public() }).collect();
+    let transports: Vec<_> = (0 .. 2).map(|_| { net.add_node() }).collect();
+    let first = dht::RoutingGift { addr: transports[0].addr(), key: publics[0] };
+    let mut nodes = Vec::new();
+    for ((i, t), k) in transports.into_iter().enumerate().zip(keys) {
+        let config = dht::NodeConfig {
+            bootstrap: if i == 0 { Vec::new() } else { vec![first] },
+            use_default_bootstrap: false,
+            send_period_ms: 50,
+            state_dir: if i == 0 { None } else { Some(dir.clone()) },
+            .. dht::NodeConfig::default()
+        };
+        nodes.push(dht::start_node(k, &config, t, Arc::new(SystemClock),
+                                   Box::new(rng::SeededRng::new(i as u64))).unwrap());
+    }
+    std::thread::sleep_ms(200);
+    let node = nodes.pop().unwrap();
+    let other = node.clone();
+    node.shutdown();
+    // Node 1 has saved its peers, and left the network, as far as
+    // every clone is concerned.
+    let mut peers = dir.clone();
+    peers.push("peers");
+    assert!(std::fs::metadata(&peers).is_ok());
+    assert!(other.receive().is_err());
+    assert!(other.rendezvous(&publics[0]).is_err());
+    assert_eq!(net.state.lock().unwrap().nodes.len(), 1);
+    // Dropping a node shuts it down too.
+    drop(nodes);
+    assert_eq!(net.state.lock().unwrap().nodes.len(), 0);
+}
+
+/// Stops a `FakeClock` when dropped, so that the nodes using it can
+/// shut down even if a test fails before it is done with them.
+#[cfg(test)]
+struct StopOnDrop(FakeClock);
+
+#[cfg(test)]
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+/// Start `num` nodes on a network that runs on a `FakeClock`, which we
+/// move on one send period at a time, so that this takes far less
+/// real time than the minutes it simulates.  Then send a message from
+/// one node and pick it up at another.
+#[cfg(test)]
+fn check_send_and_pickup(num: usize, seed: u64) {
+    use dht;
+    use secret::SecretKeyPair;
+    let clock = FakeClock::new(udp::now_ms());
+    let net = SimNetwork::with_clock(SimConfig { seed: seed, .. SimConfig::default() },
+                                     Arc::new(clock.clone()));
+    let send_period_ms = 50;
+    let keys: Vec<_> = (0 .. num).map(|_| { SecretKeyPair::generate() }).collect();
+    let publics: Vec<_> = keys.iter().map(|k| { k.public() }).collect();
+    let transports: Vec<_> = (0 .. num).map(|_| { net.add_node() }).collect();
+    let first = dht::RoutingGift { addr: transports[0].addr(), key: publics[0] };
+    let mut nodes = Vec::new();
+    for ((i, t), k) in transports.into_iter().enumerate().zip(keys) {
+        let config = dht::NodeConfig {
+            bootstrap: if i == 0 { Vec::new() } else { vec![first] },
+            use_default_bootstrap: false,
+            send_period_ms: send_period_ms,
+            .. dht::NodeConfig::default()
+        };
+        nodes.push(dht::start_node(k, &config, t, Arc::new(clock.clone()),
+                                   Box::new(SeededRng::new(seed + i as u64))).unwrap());
+    }
+    // This is dropped before the nodes are, so that none of their
+    // threads is left sleeping on a clock that nobody moves.
+    let _stopper = StopOnDrop(clock.clone());
+    // The more nodes, the longer they take to handle each period.
+    let tick = || {
+        clock.advance(send_period_ms);
+        std::thread::sleep_ms(1 + num as u32/20);
+    };
+    let alice = SecretKeyPair::generate();
+    let bob = SecretKeyPair::generate();
+    let (sender, receiver) = (&nodes[1], &nodes[num - 1]);
+
+    // Wait until both ends agree on where bob's mail goes, and can
+    // build routes to get it there.
+    let deadline = udp::now_ms() + 60*1000 + 1000*num as u64;
+    loop {
+        let ready = sender.status().unwrap().live_peers >= 3
+            && receiver.status().unwrap().live_peers >= 3
+            && sender.rendezvous(&bob.public()).unwrap()
+                == receiver.rendezvous(&bob.public()).unwrap();
+        if ready {
+            break;
+        }
+        assert!(udp::now_ms() < deadline, "the network never settled");
+        tick();
+    }
+
+    let mut plain = [0; dht::DECRYPTED_USER_MESSAGE_LENGTH];
+    plain[0] = 137;
+    let (msg_id, c) = dht::double_box(&plain, &bob.public(), alice.keypair());
+    sender.send_ciphertext(&bob.public(), &c).unwrap();
+    let deadline = udp::now_ms() + 60*1000 + 1000*num as u64;
+    let mut t = 0;
+    let mut got = None;
+    while got.is_none() {
+        assert!(udp::now_ms() < deadline, "the message never arrived");
+        if t % 20 == 0 {
+            receiver.pickup(&bob).unwrap();
+        }
+        t += 1;
+        tick();
+        got = receiver.try_receive();
+    }
+    let got = got.unwrap();
+    assert_eq!(got.destination, bob.public());
+    let (from, id, opened) = dht::double_unbox(&got.message, bob.secret()).unwrap();
+    assert_eq!(from, alice.public());
+    assert_eq!(id, msg_id);
+    assert_eq!(opened[0], 137);
+}
+
+#[test]
+fn test_send_and_pickup() {
+    check_send_and_pickup(10, 1);
+}
+
+#[test]
+fn test_send_and_pickup_crowd() {
+    // Nodes learn of each other a few at a time, so the receiver is
+    // nowhere near the sender, or bob's rendezvous, to begin with.
+    check_send_and_pickup(200, 2);
+}
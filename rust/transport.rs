@@ -0,0 +1,39 @@
+//! The interface between the `dht` and whatever actually carries its
+//! packets, which is normally a UDP socket.
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+use udp;
+use udp::{RawEncryptedMessage, ListenConfig};
+use error::Error;
+
+/// Something that can carry packets between nodes.
+pub trait Transport {
+    /// Start carrying packets.  Packets handed to the returned
+    /// `SyncSender` are sent to their `ip`, and packets addressed to
+    /// us come out of the returned `Receiver` with `ip` set to their
+    /// sender.  We expect to send about one packet every
+    /// `send_period_ms`.
+    ///
+    /// Once `stop` is set and the `SyncSender` is dropped, the
+    /// `Receiver` is closed and the returned threads finish.
+    fn open(self, send_period_ms: u64, stop: Arc<AtomicBool>)
+            -> Result<(SyncSender<RawEncryptedMessage>,
+                       Receiver<RawEncryptedMessage>,
+                       Vec<JoinHandle<()>>), Error>;
+}
+
+/// The real network, via `udp::listen`.
+pub struct UdpTransport(pub ListenConfig);
+
+impl Transport for UdpTransport {
+    fn open(self, send_period_ms: u64, stop: Arc<AtomicBool>)
+            -> Result<(SyncSender<RawEncryptedMessage>,
+                       Receiver<RawEncryptedMessage>,
+                       Vec<JoinHandle<()>>), Error> {
+        Ok(try!(udp::listen(send_period_ms, &self.0, stop)))
+    }
+}
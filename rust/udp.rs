@@ -12,14 +12,21 @@ use std::net::{ SocketAddr, SocketAddrV4 };
 // use onionsalt::crypto;
 // use onionsalt::crypto::{ToPublicKey};
 use std::io::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, channel,
                       SyncSender, sync_channel};
 use std::thread;
+use std::time::Duration;
 
 pub use onionsalt::{PACKET_LENGTH};
 
 pub const PORT: u16 = 54321;
 
+/// How long the receiver waits for a packet before checking whether
+/// it has been asked to stop.
+const READ_TIMEOUT_MS: u64 = 500;
+
 #[derive(Copy)]
 pub struct RawEncryptedMessage {
     pub ip: SocketAddr,
@@ -46,26 +53,121 @@ impl Debug for RawEncryptedMessage {
     }
 }
 
-pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
-                                              Receiver<RawEncryptedMessage>), Error> {
-    // Create the socket we will use for all communications.  If we
-    // can bind to ipv6, we will only use ipv6 for listening. I'm not
-    // sure if this is wise, but it seems best not to listen on both
-    // protocols...
-
-    // On ipv6 we only bind to the default port, since we presume that
-    // NAT isn't needed on ipv6, and don't want to store yet more
-    // routing bytes.
-    let socket = match UdpSocket::bind(("::", PORT)) {
-        Ok(s) => s,
-        _ => {
-            info!("Attempting to bind to ipv4...");
-            match UdpSocket::bind(("0.0.0.0", PORT)) {
-                Ok(s) => s,
-                _ => try!(UdpSocket::bind(("0.0.0.0", 0))) // any port in a storm
+/// Which kind of address to listen on when `ListenConfig` does not
+/// name any addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Listen on ipv6 if we can, and otherwise on ipv4.
+    PreferIpv6,
+    /// Listen on ipv4 if we can, and otherwise on ipv6.
+    PreferIpv4,
+    Ipv6Only,
+    Ipv4Only,
+}
+
+/// Where `listen` should bind its socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenConfig {
+    /// The addresses to try binding to, in order, e.g. "127.0.0.1" or
+    /// "::1".  If this is empty, we bind to every interface, using
+    /// the addresses given by `preference`.
+    pub addrs: Vec<String>,
+    pub port: u16,
+    pub preference: IpPreference,
+    /// Whether to bind to a random port if `port` is not available.
+    pub any_port: bool,
+}
+
+impl Default for ListenConfig {
+    fn default() -> ListenConfig {
+        ListenConfig {
+            addrs: Vec::new(),
+            port: PORT,
+            preference: IpPreference::PreferIpv6,
+            any_port: true,
+        }
+    }
+}
+
+impl ListenConfig {
+    /// Listen only on the loopback interface at `port`, as is handy
+    /// for running several nodes on one computer.
+    pub fn loopback(port: u16) -> ListenConfig {
+        ListenConfig {
+            addrs: vec![String::from("127.0.0.1")],
+            port: port,
+            preference: IpPreference::Ipv4Only,
+            any_port: false,
+        }
+    }
+    /// The addresses we will try, in order.
+    fn candidates(&self) -> Vec<String> {
+        if self.addrs.len() > 0 {
+            return self.addrs.clone();
+        }
+        let v: &[&str] = match self.preference {
+            IpPreference::PreferIpv6 => &["::", "0.0.0.0"],
+            IpPreference::PreferIpv4 => &["0.0.0.0", "::"],
+            IpPreference::Ipv6Only => &["::"],
+            IpPreference::Ipv4Only => &["0.0.0.0"],
+        };
+        v.iter().map(|a| { String::from(*a) }).collect()
+    }
+    fn bind(&self) -> Result<UdpSocket, Error> {
+        // By default, if we can bind to ipv6, we will only use ipv6
+        // for listening. I'm not sure if this is wise, but it seems
+        // best not to listen on both protocols...
+        let candidates = self.candidates();
+        let mut last_error = None;
+        for a in candidates.iter() {
+            match UdpSocket::bind((&a[..], self.port)) {
+                Ok(s) => { return Ok(s); },
+                Err(e) => {
+                    info!("Unable to bind to {} port {}: {}", a, self.port, e);
+                    last_error = Some(e);
+                },
             }
         }
-    };
+        // On ipv6 we normally only bind to the default port, since we
+        // presume that NAT isn't needed on ipv6, and don't want to
+        // store yet more routing bytes.  So any port in a storm is
+        // only used for the last address we tried.
+        match (self.any_port, candidates.last(), last_error) {
+            (true, Some(a), _) => UdpSocket::bind((&a[..], 0)),
+            (_, _, Some(e)) => Err(e),
+            _ => Err(Error::new(std::io::ErrorKind::Other, "no address to listen on")),
+        }
+    }
+}
+
+#[test]
+fn test_listen_config() {
+    let c = ListenConfig::default();
+    assert_eq!(c.candidates(), vec![String::from("::"), String::from("0.0.0.0")]);
+    let mut c = ListenConfig::loopback(0);
+    assert_eq!(c.candidates(), vec![String::from("127.0.0.1")]);
+    let s = c.bind().unwrap();
+    c.port = s.local_addr().unwrap().port();
+    assert!(c.bind().is_err());
+    c.any_port = true;
+    let s2 = c.bind().unwrap();
+    assert!(s2.local_addr().unwrap().port() != c.port);
+}
+
+/// Start sending and receiving packets as configured by `config`.
+/// Once `stop` is set, the receiver closes the socket, and the sender
+/// goes on pacing whatever it is given until its channel is closed,
+/// so that the packets sent while shutting down look no different
+/// from any others.  The returned threads finish once both have
+/// happened.
+pub fn listen(send_period_ms: u64, config: &ListenConfig, stop: Arc<AtomicBool>)
+              -> Result<(SyncSender<RawEncryptedMessage>,
+                         Receiver<RawEncryptedMessage>,
+                         Vec<thread::JoinHandle<()>>), Error> {
+    // Create the socket we will use for all communications.
+    let socket = try!(config.bind());
+    info!("Listening on {:?}", socket.local_addr());
+    try!(socket.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS))));
     let send_socket = try!(socket.try_clone());
 
     // Create two channels, one for sending messages from the socket,
@@ -106,7 +208,7 @@ pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
     // Actually, the receiver also sends confirmation datagrams, but
     // prior to decrypting or reading any "secret" output.
 
-    thread::spawn(move|| {
+    let sender = thread::spawn(move|| {
         // This is the sender of messages.
         let ms_period = send_period_ms;
         let mut next_time = (now_ms()/ms_period)*ms_period;
@@ -117,7 +219,14 @@ pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
                 next_time += ms_period;
             }
             next_time += ms_period;
-            let m = rs.recv().unwrap();
+            let m = match rs.recv() {
+                Ok(m) => m,
+                Err(_) => {
+                    // No one is left to give us messages to send.
+                    info!("Quitting sender since the channel is closed");
+                    return;
+                },
+            };
             // println!("Sending to {}", m.ip);
             match send_socket.send_to(&m.data, &m.ip) {
                 Ok(sent) => {
@@ -131,16 +240,31 @@ pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
             }
         }
     });
-    thread::spawn(move|| {
+    let receiver = thread::spawn(move|| {
         // This is the receiver of messages.  It listens on the
         // socket, and decrypts messages with the ephemeral session
         // key prior to forwarding the contents on through the
         // channel.
         let mut buf = [0; PACKET_LENGTH];
         loop {
-            // We assume that when we fail on a receive, the socket must
-            // have gone down, and we should exit this thread.
-            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            if stop.load(Ordering::SeqCst) {
+                info!("Closing socket");
+                return;
+            }
+            // We assume that when we fail on a receive, other than by
+            // timing out, the socket must have gone down, and we
+            // should exit this thread.
+            let (amt, src) = match socket.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut => {
+                    continue;
+                },
+                Err(e) => {
+                    error!("Quitting now because {:?}", e);
+                    return;
+                },
+            };
             if amt == PACKET_LENGTH {
                 // println!("I got a packet from {}", src);
                 if let Err(e) = tr.send(RawEncryptedMessage{ ip: normalize(src), data: buf }) {
@@ -154,7 +278,7 @@ pub fn listen(send_period_ms: u64) -> Result<(SyncSender<RawEncryptedMessage>,
             }
         }
     });
-    Ok((ts, rr))
+    Ok((ts, rr, vec![sender, receiver]))
 }
 
 /// The `EPOCH` is when time begins.  We have not facilities for